@@ -0,0 +1,2 @@
+
+complete -c cryptenv -n "__fish_seen_subcommand_from env; and __fish_seen_subcommand_from add get alias" -f -a "(cryptenv __complete_names (commandline -ct))"
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use aes_gcm::{
+    aead::{rand_core::RngCore, OsRng},
+    Aes256Gcm, Key,
+};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::store::{self, DecryptError};
+
+/// a self-contained, passphrase-encrypted set of variables for moving secrets between machines
+/// without moving the key. made by `project export --format cryptenv`, read by
+/// `env import --format cryptenv`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    /// bumped whenever the format changes, so old versions of cryptenv can refuse newer bundles
+    version: u32,
+    /// PBKDF2-HMAC-SHA256 rounds used to turn the passphrase into a key
+    iterations: u32,
+    salt: String,
+    /// the variables as a JSON object, encrypted the same way as values in the store
+    data: String,
+}
+
+const VERSION: u32 = 1;
+const ITERATIONS: u32 = 600_000;
+
+impl Bundle {
+    /// encrypt `vars` (store name -> value) with a key derived from `passphrase`
+    pub fn seal(vars: &HashMap<&str, &str>, passphrase: &str) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut key = derive_key(passphrase, &salt, ITERATIONS);
+        let mut json = serde_json::to_string(vars).expect("Could not serialize variables");
+        let data = store::encrypt_with(&key, &json);
+
+        key.zeroize();
+        json.zeroize();
+
+        Bundle {
+            version: VERSION,
+            iterations: ITERATIONS,
+            salt: BASE64_STANDARD.encode(salt),
+            data,
+        }
+    }
+
+    /// decrypt the variables in this bundle. a wrong passphrase shows up as
+    /// `DecryptError::WrongKey`
+    pub fn open(&self, passphrase: &str) -> Result<HashMap<String, String>, BundleError> {
+        if self.version != VERSION {
+            return Err(BundleError::UnsupportedVersion(self.version));
+        }
+
+        let salt = BASE64_STANDARD
+            .decode(&self.salt)
+            .map_err(|_| BundleError::Decrypt(DecryptError::InvalidBase64))?;
+
+        let mut key = derive_key(passphrase, &salt, self.iterations);
+        let json = store::decrypt_with(&key, &self.data);
+        key.zeroize();
+
+        let mut json = json.map_err(BundleError::Decrypt)?;
+        let vars = serde_json::from_str(&json).map_err(|_| BundleError::Malformed);
+        json.zeroize();
+
+        vars
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleError {
+    UnsupportedVersion(u32),
+    Decrypt(DecryptError),
+    Malformed,
+}
+
+impl std::fmt::Display for BundleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BundleError::UnsupportedVersion(v) => write!(
+                f,
+                "the bundle is version {v}, but this version of cryptenv only reads version {VERSION}"
+            ),
+            BundleError::Decrypt(DecryptError::WrongKey) => {
+                f.write_str("the passphrase is wrong or the bundle is corrupted")
+            }
+            BundleError::Decrypt(e) => write!(f, "{e}"),
+            BundleError::Malformed => f.write_str("the bundle's contents are malformed"),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Key<Aes256Gcm> {
+    let mut key = Key::<Aes256Gcm>::default();
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+
+    key
+}
@@ -0,0 +1,75 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, Store};
+
+/// set by `--file`/`CRYPTENV_FILE`. when it's set, the config and store are both read from and
+/// written to this one file instead of cryptenv.toml and store.json
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// bumped whenever the layout of the combined file changes
+const VERSION: u32 = 1;
+
+/// the config and the store in a single document, for keeping everything in one file that's easy
+/// to sync between machines. the key still lives in the keyring (or `CRYPTENV_KEY`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Combined {
+    version: u32,
+    #[serde(default)]
+    pub config: Config,
+    #[serde(default)]
+    pub store: Store,
+}
+
+pub fn set_path(path: PathBuf) {
+    PATH.set(path).expect("combined file is only set once");
+}
+
+pub fn path() -> Option<&'static Path> {
+    PATH.get().map(PathBuf::as_path)
+}
+
+impl Combined {
+    /// read the combined file. a missing file is empty, like a missing config or store
+    pub fn read(path: &Path) -> Self {
+        Self::try_read(path).unwrap_or_else(|e| fail!("config", "{e}"))
+    }
+
+    /// like `read`, but returns an error instead of exiting
+    pub fn try_read(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Combined {
+                version: VERSION,
+                config: Config::default(),
+                store: Store::default(),
+            });
+        }
+
+        let file = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        let combined: Self = toml::from_str(&file)
+            .map_err(|e| format!("could not parse {}: {e}", path.display()))?;
+
+        if combined.version != VERSION {
+            return Err(format!(
+                "{} is version {}, but this version of cryptenv only reads version {VERSION}",
+                path.display(),
+                combined.version
+            ));
+        }
+
+        Ok(combined)
+    }
+
+    /// the file holds every secret, so it's written like exported files are: only readable by
+    /// the owner, and never seen half written
+    pub fn save_to_disk(&self, path: &Path) {
+        let file = toml::to_string(self).expect("Could not serialize combined file");
+
+        crate::write_private(path, &file);
+    }
+}
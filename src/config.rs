@@ -2,48 +2,342 @@ use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
     fmt::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use crate::{Project, Shell};
+use crate::{
+    combined::{self, Combined},
+    Detection, Profile, Project, Rotation, Shell, Source, Store,
+};
+
+/// the config, read once by `Config::try_read`
+static CONFIG: OnceLock<Result<Config, String>> = OnceLock::new();
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
     projects: HashMap<String, Project>,
+    #[serde(default)]
     dirs: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    profiles: HashMap<String, Profile>,
+    /// groups of variables that can be loaded anywhere with `project load --bundle`. written like
+    /// profiles, but not tied to a directory
+    #[serde(default, alias = "bundle", skip_serializing_if = "HashMap::is_empty")]
+    bundles: HashMap<String, Profile>,
+    /// whether `dirs` are matched against the current directory case sensitively. defaults to
+    /// insensitive on macOS and Windows, whose filesystems are case insensitive by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    case_sensitive: Option<bool>,
+    #[serde(default)]
+    rotation: Rotation,
+    /// a command that prints the key, for keeping it in another password manager. used instead
+    /// of the keyring, but `CRYPTENV_KEY` still wins over it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_command: Option<String>,
+    /// a bundle that `project load` uses in directories under `dirs` that aren't a configured
+    /// project. without it, nothing is loaded there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_bundle: Option<String>,
+    /// check every write to the store by reading it back, like passing `--verify-after-write`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verify_after_write: Option<bool>,
+
+    /// the file this config was read from. relative `dirs` are resolved against its parent
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl Config {
+    /// create a new config with no projects, to be saved to ~/.config/cryptenv.toml
+    pub fn new(dirs: Vec<String>) -> Self {
+        Config {
+            projects: HashMap::new(),
+            dirs,
+            profiles: HashMap::new(),
+            bundles: HashMap::new(),
+            case_sensitive: None,
+            rotation: Rotation::default(),
+            key_command: None,
+            fallback_bundle: None,
+            verify_after_write: None,
+            path: Self::get_path(),
+        }
+    }
+
+    /// read the config from ~/.config/cryptenv.toml, then merge in every `*.toml` file in
+    /// ~/.config/cryptenv/ in alphabetical order. when a project is defined more than once, the
+    /// last definition wins
+    ///
+    /// with `--file`, the config is only read from the combined file
+    ///
+    /// either way, a TOML config in the `CRYPTENV_CONFIG` env variable is merged in last, so its
+    /// projects and profiles win over the files'
     pub fn read() -> Self {
-        let config_path = shellexpand::tilde("~/.config/cryptenv.toml");
+        Self::try_read().unwrap_or_else(|e| fail!("config", "{e}"))
+    }
+
+    /// like `read`, but returns an error instead of exiting when a file can't be read or parsed
+    ///
+    /// the config is only read once per run, later calls get a copy of it. that way the files
+    /// are only parsed, and their warnings printed, once
+    pub fn try_read() -> Result<Self, String> {
+        CONFIG.get_or_init(Self::read_all).clone()
+    }
+
+    /// the config files merged with `CRYPTENV_CONFIG`, read from scratch
+    fn read_all() -> Result<Self, String> {
+        let mut config = Self::read_files()?;
+
+        if let Ok(env) = std::env::var("CRYPTENV_CONFIG") {
+            let mut env: Self = toml::from_str(&env)
+                .map_err(|e| format!("could not parse CRYPTENV_CONFIG: {e}"))?;
+            env.path = PathBuf::from("CRYPTENV_CONFIG");
+
+            // there's no file for relative dirs to be relative to, so use the current directory
+            let current_dir = std::env::current_dir()
+                .map_err(|e| format!("could not get the current directory: {e}"))?;
+            for dir in &mut env.dirs {
+                if Path::new(shellexpand::tilde(dir).as_ref()).is_relative() {
+                    *dir = current_dir.join(&*dir).display().to_string();
+                }
+            }
+
+            config.merge(env);
+        }
+
+        Ok(config)
+    }
 
-        let config = std::fs::read_to_string(&*config_path).expect("Could not read config file");
+    /// read the config files, without `CRYPTENV_CONFIG`
+    fn read_files() -> Result<Self, String> {
+        if let Some(path) = combined::path() {
+            let mut config = Combined::try_read(path)?.config;
+            config.path = path.to_owned();
 
-        toml::from_str(&config).expect("Could not parse config file")
+            return Ok(config);
+        }
+
+        let mut config = Self::read_from(Self::get_path())?;
+
+        if let Ok(entries) = std::fs::read_dir(Self::get_dir()) {
+            let paths = entries
+                .map(|entry| entry.expect("Could not read config directory").path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .sorted();
+
+            for path in paths {
+                config.merge(Self::read_from(path)?);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// read the config from `path`. a missing file is treated as an empty config, so `load` works
+    /// before cryptenv has been set up
+    pub fn read_from(path: PathBuf) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Config {
+                path,
+                ..Default::default()
+            });
+        }
+
+        let config = std::fs::read_to_string(&path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+
+        let mut config: Self = toml::from_str(&config)
+            .map_err(|e| format!("could not parse {}: {e}", path.display()))?;
+        config.path = path;
+
+        Ok(config)
     }
 
+    pub fn save_to_disk(&self) {
+        if let Some(path) = combined::path() {
+            let mut combined = Combined::read(path);
+            combined.config = self.clone();
+            combined.save_to_disk(path);
+
+            return;
+        }
+
+        let config = toml::to_string(self).expect("Could not serialize config");
+
+        std::fs::create_dir_all(self.path.parent().expect("Could not get parent directory"))
+            .expect("Could not create config directory");
+
+        std::fs::write(&self.path, config).expect("Could not write config file");
+    }
+
+    /// merge `other` into this config, warning about projects defined in both
+    fn merge(&mut self, other: Config) {
+        // other's relative dirs are relative to other's file, so resolve them before moving them
+        for dir in other.config_dirs() {
+            let dir = dir.display().to_string();
+
+            if !self.dirs.contains(&dir) {
+                self.dirs.push(dir);
+            }
+        }
+
+        if other.case_sensitive.is_some() {
+            self.case_sensitive = other.case_sensitive;
+        }
+        self.rotation.merge(other.rotation);
+        if other.key_command.is_some() {
+            self.key_command = other.key_command;
+        }
+        if other.fallback_bundle.is_some() {
+            self.fallback_bundle = other.fallback_bundle;
+        }
+        if other.verify_after_write.is_some() {
+            self.verify_after_write = other.verify_after_write;
+        }
+
+        for (name, project) in other.projects {
+            if self.projects.contains_key(&name) {
+                warn!(
+                    "project {name} is defined more than once, using the one in {}",
+                    other.path.display()
+                );
+            }
+
+            self.projects.insert(name, project);
+        }
+
+        for (name, profile) in other.profiles {
+            if self.profiles.contains_key(&name) {
+                warn!(
+                    "profile {name} is defined more than once, using the one in {}",
+                    other.path.display()
+                );
+            }
+
+            self.profiles.insert(name, profile);
+        }
+
+        for (name, bundle) in other.bundles {
+            if self.bundles.contains_key(&name) {
+                warn!(
+                    "bundle {name} is defined more than once, using the one in {}",
+                    other.path.display()
+                );
+            }
+
+            self.bundles.insert(name, bundle);
+        }
+    }
+
+    pub fn get_path() -> PathBuf {
+        PathBuf::from(shellexpand::tilde("~/.config/cryptenv.toml").into_owned())
+    }
+
+    /// the directory of extra config files that are merged into the main one
+    pub fn get_dir() -> PathBuf {
+        PathBuf::from(shellexpand::tilde("~/.config/cryptenv/").into_owned())
+    }
+
+    /// the directories projects live in: the config's `dirs`, then the ones in `CRYPTENV_DIRS`
+    /// (separated like `PATH`). `~` is expanded, and relative entries in the config are relative
+    /// to the directory the config file is in
     pub fn dirs(&self) -> Vec<PathBuf> {
+        let env_dirs = std::env::var_os("CRYPTENV_DIRS")
+            .map(|dirs| std::env::split_paths(&dirs).collect_vec())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| PathBuf::from(shellexpand::tilde(&dir.to_string_lossy()).into_owned()));
+
+        self.config_dirs()
+            .into_iter()
+            .chain(env_dirs)
+            .unique()
+            .collect()
+    }
+
+    /// the `dirs` from the config file, resolved like `dirs` does
+    fn config_dirs(&self) -> Vec<PathBuf> {
+        let config_dir = self.path.parent().unwrap_or(Path::new(""));
+
         self.dirs
             .iter()
             .map(shellexpand::tilde)
             .map(Cow::into_owned)
             .map(PathBuf::from)
+            .map(|dir| {
+                if dir.is_relative() {
+                    config_dir.join(dir)
+                } else {
+                    dir
+                }
+            })
             .collect()
     }
 
-    pub fn unset(&self, shell: Shell) -> String {
+    pub fn key_command(&self) -> Option<&str> {
+        self.key_command.as_deref()
+    }
+
+    pub fn verify_after_write(&self) -> bool {
+        self.verify_after_write.unwrap_or(false)
+    }
+
+    pub fn case_sensitive(&self) -> bool {
+        self.case_sensitive
+            .unwrap_or(!cfg!(any(target_os = "macos", target_os = "windows")))
+    }
+
+    /// unset every variable any project or profile sets, except the ones in `keep`. `store` is
+    /// needed for the variables projects get from `strip_prefix`
+    pub fn unset(&self, shell: Shell, store: &Store, keep: &[String]) -> String {
         let mut output = String::new();
         let vars: HashSet<_> = std::env::vars().map(|(k, _)| k).collect();
-
-        for key in self
+        // strip_prefix adds variables to projects, and those have to be unset too
+        let projects = self
             .projects
             .values()
+            .map(|project| {
+                let mut project = project.clone();
+                project.expand_prefix(store);
+                project
+            })
+            .collect_vec();
+        // the fallback bundle stands in for a project, so it's unset like one
+        let fallback = self
+            .fallback_bundle()
+            .and_then(|name| self.bundles.get(name))
+            .into_iter()
+            .flat_map(|bundle| bundle.keys());
+
+        // zsh can't unset readonly variables (that's the point of them), so leave them alone
+        let readonly: HashSet<_> = match shell {
+            Shell::Zsh => projects
+                .iter()
+                .filter(|proj| proj.is_readonly())
+                .flat_map(|proj| {
+                    let profiles = proj.profiles().filter_map(|name| self.profiles.get(name));
+
+                    proj.keys()
+                        .chain(profiles.flat_map(|profile| profile.keys()))
+                })
+                .collect(),
+            Shell::Fish => HashSet::new(),
+        };
+
+        for key in projects
+            .iter()
             .flat_map(|proj| proj.keys())
-            .dedup()
-            .filter(|key| vars.contains(key.to_owned()))
+            .chain(self.profiles.values().flat_map(|profile| profile.keys()))
+            .chain(fallback)
+            .unique()
+            .filter(|key| vars.contains(key.to_owned()) && !readonly.contains(key))
+            .filter(|key| !keep.iter().any(|k| k == key))
         {
             let res = match shell {
                 Shell::Zsh => writeln!(output, "unset {key}"),
@@ -60,7 +354,191 @@ impl Config {
         &self.projects
     }
 
-    pub fn projects_mut(&mut self) -> &mut HashMap<String, Project> {
-        &mut self.projects
+    /// get a project with its `strip_prefix` variables and the variables of its profiles merged
+    /// in. the project's own `vars` win over `strip_prefix`, which wins over its profiles, and
+    /// earlier profiles win over later ones
+    pub fn project(&self, name: &str) -> Option<Project> {
+        let mut project = self.projects.get(name)?.clone();
+
+        // only read the store when there's a prefix to look for
+        if project.strip_prefix().is_some() {
+            project.expand_prefix(&Store::read());
+        }
+
+        for profile_name in self.projects[name].profiles() {
+            let profile = self.get_profile(profile_name).unwrap_or_else(|| {
+                fail!(
+                    "config",
+                    "project {name} uses profile {profile_name}, which doesn't exist"
+                )
+            });
+
+            project.merge_profile(profile);
+        }
+
+        Some(project)
+    }
+
+    pub fn rotation(&self) -> &Rotation {
+        &self.rotation
+    }
+
+    pub fn profiles(&self) -> &HashMap<String, Profile> {
+        &self.profiles
+    }
+
+    pub fn get_profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    pub fn bundles(&self) -> &HashMap<String, Profile> {
+        &self.bundles
+    }
+
+    /// the store variables some project, profile or bundle uses, with aliases resolved to the
+    /// variables they point to
+    pub fn referenced(&self, store: &Store) -> HashSet<String> {
+        let profiles = self
+            .profiles
+            .values()
+            .chain(self.bundles.values())
+            .flat_map(|profile| profile.iter().map(|(_, v)| v))
+            .filter_map(|v| match Source::parse(v) {
+                Source::Store(name) => Some(name),
+                Source::Env(_) | Source::Cmd(_) => None,
+            });
+
+        // strip_prefix adds variables to projects, so expand it to see what's used
+        self.projects
+            .values()
+            .flat_map(|project| {
+                let mut project = project.clone();
+                project.expand_prefix(store);
+
+                project
+                    .store_variables()
+                    .map(|name| store.target(name).to_owned())
+                    .collect_vec()
+            })
+            .chain(profiles.map(|name| store.target(name).to_owned()))
+            .collect()
+    }
+
+    pub fn fallback_bundle(&self) -> Option<&str> {
+        self.fallback_bundle.as_deref()
+    }
+
+    /// the `fallback_bundle` as a project, when the current directory is under one of the `dirs`
+    /// but isn't a configured project
+    pub fn fallback_project(&self) -> Option<Project> {
+        let name = self.fallback_bundle()?;
+
+        match Project::detect(self) {
+            Detection::Outside => return None,
+            Detection::Project(project) if self.projects.contains_key(&project) => return None,
+            Detection::Root(_) | Detection::Project(_) => {}
+        }
+
+        let Some(bundle) = self.bundles.get(name) else {
+            warn!("the fallback bundle {name} doesn't exist");
+            return None;
+        };
+        verbose!("no project here, using the fallback bundle {name}");
+
+        let mut project = Project::default();
+        project.merge_profile(bundle);
+        Some(project)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{aead::OsRng, Aes256Gcm, KeyInit};
+
+    use super::*;
+
+    /// a fresh directory under the system temp dir for one test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cryptenv-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn empty_config_loads_nothing() {
+        let config = Config::default();
+        let store = Store::default();
+
+        assert_eq!(config.unset(Shell::Zsh, &store, &[]), "");
+        assert_eq!(config.unset(Shell::Fish, &store, &[]), "");
+        assert!(config.fallback_project().is_none());
+        assert_eq!(Project::default().to_shell(&store, Shell::Zsh), "");
+    }
+
+    #[test]
+    fn unset_includes_strip_prefix_variables() {
+        let config: Config = toml::from_str(
+            r#"
+            [projects.app]
+            strip_prefix = "APP_"
+            [projects.other.vars]
+            X = "X"
+            "#,
+        )
+        .unwrap();
+
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let mut store = Store::default();
+        store.add_with(&key, "APP_CRYPTENV_TEST_TOKEN".to_owned(), "secret");
+        std::env::set_var("CRYPTENV_TEST_TOKEN", "secret");
+
+        let unset = config.unset(Shell::Zsh, &store, &[]);
+
+        assert!(unset
+            .lines()
+            .any(|line| line == "unset CRYPTENV_TEST_TOKEN"));
+    }
+
+    #[test]
+    fn unset_includes_the_fallback_bundle_without_projects() {
+        let config: Config = toml::from_str(
+            r#"
+            dirs = ["~/code"]
+            fallback_bundle = "base"
+            [bundles.base.vars]
+            CRYPTENV_TEST_FALLBACK = "DB"
+            "#,
+        )
+        .unwrap();
+        std::env::set_var("CRYPTENV_TEST_FALLBACK", "secret");
+
+        let unset = config.unset(Shell::Zsh, &Store::default(), &[]);
+
+        assert!(unset
+            .lines()
+            .any(|line| line == "unset CRYPTENV_TEST_FALLBACK"));
+    }
+
+    #[test]
+    fn dirs_are_resolved_against_the_config_file() {
+        let dir = temp_dir("config-dirs");
+        let path = dir.join("cryptenv.toml");
+        std::fs::write(&path, r#"dirs = ["code", "~/work", "/srv/projects"]"#).unwrap();
+
+        let config = Config::read_from(path).unwrap();
+
+        // config_dirs, since dirs() also adds CRYPTENV_DIRS from the environment
+        assert_eq!(
+            config.config_dirs(),
+            vec![
+                dir.join("code"),
+                dirs::home_dir().unwrap().join("work"),
+                PathBuf::from("/srv/projects"),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
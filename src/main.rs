@@ -1,28 +1,172 @@
+/// prints a diagnostic to stderr, but only when `--verbose` is passed
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!("cryptenv: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// prints a warning to stderr. everything that isn't a command's output goes to stderr, because
+/// the output of `project load` is eval'd by the shell
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        eprintln!("cryptenv: warning: {}", format_args!($($arg)*))
+    };
+}
+
+/// prints an error to stderr, as text or JSON depending on `--error-format`, and exits. the first
+/// argument is the kind of error, for programs that call cryptenv to branch on
+macro_rules! fail {
+    ($kind:expr, $($arg:tt)*) => {
+        crate::fail($kind, format_args!($($arg)*), None)
+    };
+}
+
+mod bundle;
+mod combined;
 mod config;
+mod profile;
 mod project;
+mod rotation;
+mod selftest;
+mod setup;
+mod stats;
 mod store;
 
-use clap::{Parser, Subcommand, ValueEnum};
-use std::process::{self};
+use bundle::Bundle;
+use clap::{
+    builder::TypedValueParser, error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use stats::Stats;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{self},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use zeroize::Zeroize;
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 
 pub use config::Config;
-pub use project::Project;
+pub use profile::Profile;
+pub use project::{Detection, Project, Source};
+pub use rotation::Rotation;
 pub use store::Store;
 
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// what `key reset` makes you type before deleting everything
+const RESET_CONFIRMATION: &str = "delete my secrets";
+
+/// `check` notes that the store may be slow to load past either of these
+const LARGE_STORE_VARIABLES: usize = 300;
+const LARGE_STORE_BYTES: u64 = 1024 * 1024;
+
+/// `env list --decrypt` cuts values longer than this unless `--full` is passed
+const LIST_VALUE_CHARS: usize = 80;
+
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// print timing and other diagnostics to stderr. never prints secret values
+    #[arg(short, long, global = true, default_value_t = false)]
+    verbose: bool,
+
+    /// use a separate store and key for this environment, e.g. dev or prod. the store is saved to
+    /// store.<env>.json instead of store.json
+    #[arg(long, global = true, env = "CRYPTENV_ENV")]
+    env: Option<String>,
+
+    /// keep the config and the store together in this one file instead of cryptenv.toml and
+    /// store.json
+    #[arg(long, global = true, env = "CRYPTENV_FILE")]
+    file: Option<PathBuf>,
+
+    /// give up on the keyring after this many seconds instead of waiting on it forever
+    #[arg(long, global = true, env = "CRYPTENV_TIMEOUT", value_name = "SECONDS", default_value_t = store::DEFAULT_TIMEOUT_SECS)]
+    timeout: u64,
+
+    /// how long to wait for another cryptenv that's writing the store before giving up. gives
+    /// up with exit code 75, so scripts can retry
+    #[arg(long, global = true, env = "CRYPTENV_STORE_LOCK_TIMEOUT", value_name = "SECONDS", default_value_t = store::DEFAULT_LOCK_TIMEOUT_SECS)]
+    store_lock_timeout: u64,
+
+    /// how errors are printed to stderr. json prints one `{"error", "kind", "hint"}` object, for
+    /// programs that run cryptenv
+    #[arg(long, global = true, value_enum, default_value_t = ErrorFormat::Human)]
+    error_format: ErrorFormat,
+
+    /// after changing the store, read it back and check the change landed, and that an added
+    /// value decrypts to what was added. fails with exit code 74 if it didn't. can also be turned
+    /// on with `verify_after_write = true` in the config
+    #[arg(long, global = true)]
+    verify_after_write: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Checks every project and makes sure that the env variables they're referencing are all in
     /// the cryptenv store
-    Check,
+    Check {
+        /// only check these projects (and the profiles they use) instead of every project
+        projects: Vec<String>,
+
+        /// also warn about store variables that no project, profile or bundle uses, so the store
+        /// doesn't fill up with ones nothing needs anymore
+        #[arg(long)]
+        strict: bool,
+
+        /// like --strict, but unused store variables make the check fail
+        #[arg(long)]
+        fail_on_unreferenced: bool,
+    },
     /// set up cryptenv for your shell
-    Init { shell: Shell },
+    Init {
+        shell: Shell,
+
+        /// also set up tab completion for cryptenv's commands and the names in the store
+        #[arg(long)]
+        with_completions: bool,
+    },
+    /// interactively create a config file, set up the key, and show how to hook cryptenv into
+    /// your shell
+    Setup,
+
+    /// count the variables in the store and what uses them. never prints names or values
+    Stats {
+        /// print the counts as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// also append the counts as a line of JSON to this file, to keep a local history of them.
+        /// nothing is ever sent anywhere
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+    },
+
+    /// print the names in the store starting with PREFIX, one per line, for shell completions.
+    /// only reads the store file, so it's fast and never touches the key
+    #[command(name = "__complete_names", hide = true)]
+    CompleteNames {
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+
+    /// check that encryption, the keyring, and the store file work, without touching your key or
+    /// store. the first thing to run when nothing works
+    Selftest,
 
     /// edit the env variables in the cryptenv store
     Env {
@@ -34,29 +178,156 @@ enum Commands {
         #[command(subcommand)]
         subcommand: ProjectSubcommand,
     },
+    /// inspect the encryption key
+    Key {
+        #[command(subcommand)]
+        subcommand: KeySubcommand,
+    },
 }
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Shell {
     Zsh,
     Fish,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Dotenv,
+    Cryptenv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    Cryptenv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
 #[derive(Subcommand, Clone, Debug)]
 pub enum ProjectSubcommand {
     /// loads the env for the current directory. you probably shouldn't run this directly
     ///
     /// returns the shell script to set the environment variables for the current project
-    Load { shell: Shell },
+    Load {
+        /// the shell to print the script for. can be left out for projects with a `shell` in
+        /// the config
+        shell: Option<Shell>,
+
+        /// set KEY to VALUE for this load only, overriding the project's value if it has one.
+        /// nothing is written to the store. can be passed multiple times. the variables of a
+        /// `readonly` project can't be overridden
+        #[arg(long = "set", value_name = "KEY=VALUE", value_parser = SecretOverrideParser)]
+        overrides: Vec<(String, String)>,
+
+        /// only load the variables that come from this profile
+        #[arg(short, long)]
+        profile: Option<String>,
+
+        /// don't set or unset KEY, on top of the project's `skip` list. can be passed multiple
+        /// times
+        #[arg(long, value_name = "KEY")]
+        skip: Vec<String>,
+
+        /// remind you (on stderr) to rotate store variables older than this many days. replaces
+        /// `rotation.max_age_days` from the config, but not the max ages of tags
+        #[arg(long, value_name = "DAYS")]
+        max_age: Option<u64>,
+
+        /// load this bundle from the config instead of the project in the current directory. only
+        /// sets the bundle's variables, without unsetting anything
+        #[arg(short, long, conflicts_with = "profile")]
+        bundle: Option<String>,
+
+        /// print the script to stderr with every value redacted, instead of printing it for the
+        /// shell to run. for checking what load would do
+        #[arg(long)]
+        show: bool,
+    },
     /// gets the name of the project in CWD
-    /// exits with status code 1 if we're not in a project
+    /// exits with status code 1 if we're not in a project, saying why on stderr
     Name,
     /// lists all the names of the environment variables in the current project
     /// you can either pass in the project, or use the project in CWD
-    List { project: String },
+    List {
+        project: Option<String>,
+
+        /// show the project as a tree of its variables and where they come from, marking the
+        /// ones missing from the store. shows every project if there isn't one in CWD
+        #[arg(short, long, default_value_t = false)]
+        tree: bool,
+    },
     /// returns the environment variables of the current project in the
     /// `KEY=VALUE` format used by .env files
-    Export { project: String },
+    Export {
+        #[arg(required_unless_present = "all")]
+        project: Option<String>,
+
+        /// export every project in the config. needs --out-dir
+        #[arg(long, conflicts_with_all = ["project", "profile"], requires = "out_dir")]
+        all: bool,
+
+        /// write each project to a file in this directory (PROJECT.env, PROJECT.env.example
+        /// with --example, or PROJECT.json for a cryptenv bundle) instead of printing it
+        #[arg(long, value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+
+        /// with --out-dir, leave files that already have the same content alone, so their
+        /// modification time doesn't change
+        #[arg(long, requires = "out_dir")]
+        only_changed: bool,
+
+        /// only print the names, as `KEY=` lines with empty values. this never decrypts anything,
+        /// so the output is safe to share as a template .env file
+        #[arg(long, default_value_t = false)]
+        names_only: bool,
+
+        /// print a .env.example style template: the names with empty values, and each
+        /// variable's note as a comment above it. like --names-only, this never decrypts anything
+        #[arg(long, default_value_t = false, conflicts_with = "names_only")]
+        example: bool,
+
+        /// only export the variables that come from this profile
+        #[arg(short, long)]
+        profile: Option<String>,
+
+        /// dotenv prints `KEY=VALUE` lines. cryptenv prints a bundle of the project's store
+        /// variables encrypted with a passphrase (from CRYPTENV_PASSPHRASE, or asked for), which
+        /// another machine can read with `cryptenv env import --format cryptenv`
+        #[arg(short, long, default_value_t = ExportFormat::Dotenv, value_enum)]
+        format: ExportFormat,
+
+        /// put this in front of every exported name, e.g. VITE_. names changed with --rename
+        /// don't get it
+        #[arg(long, default_value = "")]
+        prefix: String,
+
+        /// export the variable OLD as NEW instead. can be passed more than once
+        #[arg(long, value_name = "OLD=NEW", value_parser = parse_rename)]
+        rename: Vec<(String, String)>,
+
+        /// write the export to this named pipe (made with `mkfifo`) instead of printing it, for
+        /// another process to read once. the secrets never land in a regular file or in argv.
+        /// waits until something opens the pipe for reading
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["all", "out_dir", "fd"])]
+        to_fifo: Option<PathBuf>,
+
+        /// like --to-fifo, but write to this already open file descriptor, e.g. 3 with
+        /// `cryptenv project export app --fd 3 3>&1 | consumer`
+        #[arg(long, value_name = "N", conflicts_with_all = ["all", "out_dir"])]
+        fd: Option<u32>,
+    },
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -79,54 +350,698 @@ pub enum EnvSubcommand {
     Get {
         /// the name of the environment variable. automatically uppercased
         name: String,
+
+        /// encode the value before printing it
+        #[arg(short, long)]
+        encoding: Option<Encoding>,
+
+        /// treat the value as a JSON object and only print this field of it
+        #[arg(short, long)]
+        field: Option<String>,
+    },
+    /// give a variable in the store another name. both names share one encrypted value, so
+    /// changing either changes both
+    Alias {
+        /// the new name. automatically uppercased
+        name: String,
+        /// the variable it points to
+        existing: String,
     },
     /// lists all the environment variables in the store
     List {
         /// whether to show the value as well
         #[arg(short, long, default_value_t = false)]
         decrypt: bool,
+        /// with --decrypt, print values in full instead of cutting long and multiline ones short
+        #[arg(long, default_value_t = false, requires = "decrypt")]
+        full: bool,
+    },
+    /// import secrets that are already in your OS keyring, e.g. ones saved by other tools
+    Import {
+        /// the keyring service the secrets are saved under
+        #[arg(
+            long,
+            value_name = "SERVICE",
+            requires = "accounts",
+            required_unless_present = "format"
+        )]
+        from_keyring: Option<String>,
+
+        /// the keyring accounts to import. the keyring can't list the accounts for a service on
+        /// every platform, so they have to be given here. use ACCOUNT=NAME to store an account
+        /// under a different name, otherwise the uppercased account name is used
+        #[arg(value_name = "ACCOUNT[=NAME]", requires = "from_keyring")]
+        accounts: Vec<String>,
+
+        /// import a bundle from `cryptenv project export --format cryptenv`, read from stdin. the
+        /// passphrase is read from CRYPTENV_PASSPHRASE, or asked for
+        #[arg(long, conflicts_with = "from_keyring")]
+        format: Option<ImportFormat>,
+
+        #[arg(short, long, default_value_t = false)]
+        /// overwrite values that already exist
+        overwrite: bool,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum KeySubcommand {
+    /// prints a short hash of the key. if two machines print the same fingerprint, they have the
+    /// same key and can decrypt each other's stores
+    Fingerprint,
+    /// deletes the key and the store, for starting over after the key is lost. values can't be
+    /// decrypted without the key they were encrypted with, so the store has to go too
+    Reset {
+        /// confirms that the whole store should be deleted along with the key
+        #[arg(long, default_value_t = false)]
+        wipe_store: bool,
     },
 }
 
+impl Encoding {
+    fn encode(&self, value: &str) -> String {
+        use base64::prelude::*;
+
+        match self {
+            Encoding::Hex => hex::encode(value),
+            Encoding::Base64 => BASE64_STANDARD.encode(value),
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no shells are skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no formats are skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+impl ExportFormat {
+    /// every format, in the order `--help` lists them
+    pub fn all() -> &'static [Self] {
+        Self::value_variants()
+    }
+
+    /// the extension of the files `export --out-dir` writes in this format
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Dotenv => "env",
+            ExportFormat::Cryptenv => "json",
+        }
+    }
+
+    /// `project` in this format. the passphrase for a cryptenv bundle is only asked for once, and
+    /// kept in `passphrase` for the next project
+    fn render(&self, project: &Project, store: &Store, passphrase: &mut Option<String>) -> String {
+        match self {
+            ExportFormat::Dotenv => project.render(store, |k, v| format!("{}={}", k, v)),
+            ExportFormat::Cryptenv => {
+                let passphrase = passphrase.get_or_insert_with(|| read_passphrase(true));
+                export_bundle(project, store, passphrase)
+            }
+        }
+    }
+}
+
 impl Shell {
+    /// every shell cryptenv supports, in the order `--help` lists them
+    pub fn all() -> &'static [Self] {
+        Self::value_variants()
+    }
+
     fn init(&self) -> &'static str {
         match self {
             Shell::Zsh => include_str!("../shells/init.zsh"),
             Shell::Fish => include_str!("../shells/init.fish"),
         }
     }
+
+    /// the completion script for this shell: clap's completions for every command, plus
+    /// completing store names with `cryptenv __complete_names`
+    fn completions(&self) -> String {
+        let (shell, names) = match self {
+            Shell::Zsh => (
+                clap_complete::Shell::Zsh,
+                include_str!("../shells/completions.zsh"),
+            ),
+            Shell::Fish => (
+                clap_complete::Shell::Fish,
+                include_str!("../shells/completions.fish"),
+            ),
+        };
+
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Args::command(), "cryptenv", &mut script);
+
+        let mut script = String::from_utf8(script).expect("completions are valid utf8");
+
+        // clap completes every argument with zsh's `_default`, so point the arguments that take
+        // a store name at `_cryptenv_names` instead
+        if let Shell::Zsh = self {
+            script = script
+                .lines()
+                .map(|line| {
+                    let is_name =
+                        line.starts_with("':name -- ") || line.starts_with("':existing -- ");
+                    match line.strip_suffix(":_default' \\") {
+                        Some(start) if is_name => format!("{start}:_cryptenv_names' \\"),
+                        _ => line.to_owned(),
+                    }
+                })
+                .join("\n");
+            script.push('\n');
+        }
+
+        script.push_str(names);
+
+        script
+    }
+
+    /// the command that sets `key` to `value` in this shell. the value is quoted, so it's safe to
+    /// `eval` no matter what characters it has in it
+    pub fn export(&self, key: &str, value: &str) -> String {
+        match self {
+            Shell::Zsh => format!("export {}={}", key, self.quote(value)),
+            Shell::Fish => format!("set -gx {} {};", key, self.quote(value)),
+        }
+    }
+
+    /// like `export`, but the variable can't be changed or unset for the rest of the session.
+    /// fish doesn't have readonly variables, so there it's a normal export
+    pub fn export_readonly(&self, key: &str, value: &str) -> String {
+        match self {
+            // -g because the script is eval'd inside the hook function, where typeset would make
+            // a local. loading the project again skips variables that are already readonly with
+            // the same value (unsetting in a subshell only fails for readonly ones), instead of
+            // failing to set them
+            Shell::Zsh => {
+                let value = self.quote(value);
+                format!(
+                    "{{ [ \"${{{key}-}}\" = {value} ] && ! (unset {key}) 2>/dev/null; }} || \
+                    typeset -grx {key}={value}"
+                )
+            }
+            Shell::Fish => self.export(key, value),
+        }
+    }
+
+    fn quote(&self, value: &str) -> String {
+        match self {
+            Shell::Zsh => format!("'{}'", value.replace('\'', r"'\''")),
+            Shell::Fish => format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'")),
+        }
+    }
+
+    /// the rc file for this shell, and the line to add to it to load cryptenv
+    fn rc_line(&self) -> (&'static str, &'static str) {
+        match self {
+            Shell::Zsh => ("~/.zshrc", r#"eval "$(cryptenv init zsh)""#),
+            Shell::Fish => ("~/.config/fish/config.fish", "cryptenv init fish | source"),
+        }
+    }
+}
+
+/// prints an error for every variable in `vars` that isn't in the store, returning whether there
+/// were any
+fn check_variables<'a>(
+    owner: &str,
+    vars: impl Iterator<Item = (&'a str, &'a str)>,
+    store: &Store,
+) -> bool {
+    let mut found_error = false;
+
+    for (key, value) in vars.sorted() {
+        let Source::Store(variable) = Source::parse(value) else {
+            continue;
+        };
+
+        if store.contains(variable) {
+            continue;
+        }
+
+        found_error = true;
+        println!(
+            "cryptenv: variable {} defined in {} not found in store",
+            variable, owner
+        );
+
+        // `KEY = "KEY"` is fine, but if KEY is missing it's usually because it was never added,
+        // not because the mapping is wrong
+        if variable == key {
+            println!(
+                "  hint: {} is stored under the name it's exported as. add it with `cryptenv env add {} <value>`",
+                key, key
+            );
+        }
+    }
+
+    found_error
+}
+
+/// reads the passphrase for a bundle from CRYPTENV_PASSPHRASE, or asks for it. when making a
+/// bundle, it's asked for twice to catch typos
+fn read_passphrase(confirm: bool) -> String {
+    if let Ok(passphrase) = std::env::var("CRYPTENV_PASSPHRASE") {
+        return passphrase;
+    }
+
+    let passphrase = rpassword::prompt_password("passphrase: ").expect("Could not read passphrase");
+
+    if passphrase.is_empty() {
+        fail!("passphrase", "the passphrase can't be empty");
+    }
+
+    if confirm {
+        let again =
+            rpassword::prompt_password("confirm passphrase: ").expect("Could not read passphrase");
+
+        if again != passphrase {
+            fail!("passphrase", "the passphrases don't match");
+        }
+    }
+
+    passphrase
+}
+
+/// a cryptenv bundle of `project`'s store variables, as JSON
+fn export_bundle(project: &Project, store: &Store, passphrase: &str) -> String {
+    // bundles hold store variables by their store name, so the other machine's config can use
+    // them as-is. `env:` and `cmd:` variables don't come from the store, so they're left out
+    // without being read or run
+    let decrypted = store.decrypt_all(project.store_variables());
+
+    let values = project
+        .store_variables()
+        .map(|name| match decrypted.get(name) {
+            Some(Ok(variable)) => (name, variable.value()),
+            Some(Err(e)) => fail(
+                e.kind(),
+                format_args!("could not decrypt {name}: {e}"),
+                e.hint(),
+            ),
+            None => fail!("not_found", "variable {name} not found"),
+        })
+        .collect();
+
+    let bundle = Bundle::seal(&values, passphrase);
+    let mut output = serde_json::to_string(&bundle).expect("Could not serialize bundle");
+    output.push('\n');
+
+    output
+}
+
+/// write `contents` to `path` through a temporary file, so nothing ever sees a half written file.
+/// with `only_changed`, a file that already has these contents isn't touched. returns whether the
+/// file was written
+fn write_if_changed(path: &Path, contents: &str, only_changed: bool) -> bool {
+    if only_changed && std::fs::read(path).is_ok_and(|old| old == contents.as_bytes()) {
+        return false;
+    }
+
+    let parent = path.parent().expect("Could not get parent directory");
+    std::fs::create_dir_all(parent).expect("Could not create output directory");
+    write_private(path, contents);
+
+    true
+}
+
+/// write `contents` to `path` through a temporary file that only the owner can read, then move
+/// it into place, so nothing ever sees a half written file. for files that can hold secrets
+fn write_private(path: &Path, contents: &str) {
+    let parent = path.parent().expect("Could not get parent directory");
+    let file_name = path.file_name().expect("path has a file name");
+    let tmp = parent.join(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    // a temporary file left over from before could have looser permissions, so start from a
+    // new one
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp).expect("Could not write file");
+    file.write_all(contents.as_bytes())
+        .expect("Could not write file");
+    std::fs::rename(&tmp, path).expect("Could not write file");
+}
+
+/// shorten a value for `env list --decrypt`: only the first line of a multiline value, and at
+/// most `LIST_VALUE_CHARS` of it, with a note of how much was left out
+fn summarize(value: &str) -> String {
+    let mut lines = value.lines();
+    let first = lines.next().unwrap_or_default();
+    let more_lines = lines.count();
+
+    let mut summary: String = first.chars().take(LIST_VALUE_CHARS).collect();
+    let more_chars = first.chars().count().saturating_sub(LIST_VALUE_CHARS);
+
+    if more_lines > 0 {
+        summary.push_str(&format!("…({more_lines} more lines)"));
+    } else if more_chars > 0 {
+        summary.push_str(&format!("…({more_chars} more chars)"));
+    }
+
+    summary
+}
+
+/// hand `contents` to the process reading `path`, a FIFO or `/dev/fd/N`. regular files are refused
+/// so the secrets never end up on disk, and if the reader goes away the rest of the output is
+/// dropped rather than written anywhere else
+fn write_handoff(path: &Path, mut contents: String) {
+    let file_type = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) => fail!("export", "could not find {}: {e}", path.display()),
+    };
+    if file_type.is_file() || file_type.is_dir() {
+        fail(
+            "export",
+            format_args!("{} isn't a pipe", path.display()),
+            Some("make one with `mkfifo`, so the secrets don't get written to disk"),
+        );
+    }
+
+    verbose!("waiting for a reader on {}", path.display());
+    let result = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    contents.zeroize();
+
+    match result {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+            fail!(
+                "export",
+                "the reader closed {} before reading everything",
+                path.display()
+            );
+        }
+        Err(e) => fail!("export", "could not write to {}: {e}", path.display()),
+    }
+}
+
+/// parses a `KEY=VALUE` argument for `load --set`
+/// the key is pasted into the script the shell evals, so it has to be a plain variable name
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = arg.split_once('=') else {
+        return Err("expected KEY=VALUE".to_owned());
+    };
+    check_name(key)?;
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// parses an `OLD=NEW` argument for `export --rename`. both have to be variable names
+fn parse_rename(arg: &str) -> Result<(String, String), String> {
+    let (old, new) = parse_override(arg)?;
+    check_name(&new)?;
+
+    Ok((old, new))
+}
+
+/// names have to match `[A-Za-z_][A-Za-z0-9_]*`, so they're safe to put in a shell script as-is
+fn check_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "{name} isn't a valid variable name: use letters, numbers and _, not starting with a number"
+        )),
+    }
+}
+
+/// `parse_override` for `--set`, whose values are secrets. clap's own errors echo the invalid
+/// value, so this makes its own that don't
+#[derive(Clone)]
+struct SecretOverrideParser;
+
+impl TypedValueParser for SecretOverrideParser {
+    type Value = (String, String);
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let arg = arg.map(ToString::to_string).unwrap_or_default();
+
+        value
+            .to_str()
+            .ok_or_else(|| "expected valid utf8".to_owned())
+            .and_then(parse_override)
+            .map_err(|e| {
+                clap::Error::raw(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "invalid value {} for '{arg}': {e}\n",
+                        store::Redacted(value)
+                    ),
+                )
+                .with_cmd(cmd)
+            })
+    }
+}
+
+/// print an error and exit. use the `fail!` macro unless there's a hint to go with it
+fn fail(kind: &str, error: impl std::fmt::Display, hint: Option<&str>) -> ! {
+    fail_with_code(1, kind, error, hint)
+}
+
+/// `fail`, but exiting with `code`, for errors that scripts should be able to tell apart
+fn fail_with_code(code: i32, kind: &str, error: impl std::fmt::Display, hint: Option<&str>) -> ! {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let error = serde_json::json!({ "error": error.to_string(), "kind": kind, "hint": hint });
+        eprintln!("{error}");
+    } else {
+        eprintln!("cryptenv: {error}");
+        if let Some(hint) = hint {
+            eprintln!("  hint: {hint}");
+        }
+    }
+
+    process::exit(code);
 }
 
 fn main() {
-    let args = Args::parse();
+    let args = Args::try_parse().unwrap_or_else(|e| {
+        // the arguments didn't parse, so look for `--error-format json` by hand
+        let json = std::env::args()
+            .tuple_windows()
+            .any(|(flag, value)| flag == "--error-format" && value == "json")
+            || std::env::args().any(|arg| arg == "--error-format=json");
+
+        if !json || !e.use_stderr() {
+            e.exit();
+        }
+
+        let message = e.render().to_string();
+        let error = serde_json::json!({ "error": message.trim(), "kind": "usage", "hint": null });
+        eprintln!("{error}");
+        process::exit(e.exit_code());
+    });
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    if args.error_format == ErrorFormat::Json {
+        JSON_ERRORS.store(true, Ordering::Relaxed);
+
+        // the `.expect`s around file and keyring access panic, so report those the same way
+        std::panic::set_hook(Box::new(|info| {
+            let error = match info.payload().downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => info
+                    .payload()
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .unwrap_or_else(|| "cryptenv panicked".to_owned()),
+            };
+            let error = serde_json::json!({ "error": error, "kind": "internal", "hint": null });
+            eprintln!("{error}");
+        }));
+    }
+    // the combined file has one store, so it can't be split up by environment like store.json
+    if args.env.is_some() && args.file.is_some() {
+        fail!(
+            "usage",
+            "--env can't be used with --file, which has a single store"
+        );
+    }
+    if let Some(env) = args.env {
+        store::set_environment(env);
+    }
+    if let Some(file) = args.file {
+        combined::set_path(file);
+    }
+    store::set_timeout(args.timeout);
+    store::set_lock_timeout(args.store_lock_timeout);
+    // a broken config only stops the commands that need it, so it isn't an error here. the key
+    // is one of those, unless it comes from the environment
+    let config = Config::try_read();
+    store::set_key_command(match std::env::var("CRYPTENV_KEY_COMMAND") {
+        Ok(command) => Ok(Some(command)),
+        Err(_) => config
+            .as_ref()
+            .map(|config| config.key_command().map(str::to_owned))
+            .map_err(Clone::clone),
+    });
+    store::set_verify_after_write(
+        args.verify_after_write || config.as_ref().is_ok_and(Config::verify_after_write),
+    );
 
     match args.command {
-        Commands::Check => {
+        Commands::Check {
+            projects,
+            strict,
+            fail_on_unreferenced,
+        } => {
             let config = Config::read();
             let store = Store::read();
             let mut found_error = false;
 
-            for (name, project) in config.projects().iter() {
-                for variable in project.variables() {
-                    if store.get(variable).is_none() {
+            for name in projects
+                .iter()
+                .filter(|p| !config.projects().contains_key(*p))
+            {
+                found_error = true;
+                println!("cryptenv: project {} doesn't exist", name);
+            }
+
+            let selected = config
+                .projects()
+                .iter()
+                .filter(|(name, _)| projects.is_empty() || projects.contains(name))
+                .sorted_by_key(|(k, _)| *k)
+                .collect_vec();
+
+            // when checking specific projects, only check the profiles they use
+            let profiles = config
+                .profiles()
+                .iter()
+                .filter(|(name, _)| {
+                    projects.is_empty()
+                        || selected
+                            .iter()
+                            .any(|(_, project)| project.profiles().contains(&name.as_str()))
+                })
+                .sorted_by_key(|(k, _)| *k)
+                .collect_vec();
+
+            for (name, project) in selected {
+                for profile in project.profiles() {
+                    if config.get_profile(profile).is_none() {
                         found_error = true;
 
                         println!(
-                            "cryptenv: variable {} defined in project {} not found in store",
-                            variable, name
+                            "cryptenv: project {} uses profile {} which doesn't exist",
+                            name, profile
                         );
                     }
                 }
+
+                found_error |= check_variables(&format!("project {name}"), project.iter(), &store);
+            }
+
+            for (name, profile) in profiles {
+                found_error |= check_variables(&format!("profile {name}"), profile.iter(), &store);
+            }
+
+            // bundles aren't used by projects, so they're only checked when checking everything
+            if projects.is_empty() {
+                for (name, bundle) in config.bundles().iter().sorted_by_key(|(k, _)| *k) {
+                    found_error |=
+                        check_variables(&format!("bundle {name}"), bundle.iter(), &store);
+                }
+
+                if let Some(name) = config.fallback_bundle() {
+                    if !config.bundles().contains_key(name) {
+                        found_error = true;
+                        println!("cryptenv: the fallback bundle {name} doesn't exist");
+                    }
+                }
+            }
+
+            for (alias, target) in store.dangling_aliases().sorted() {
+                warn!("{alias} is an alias of {target}, which isn't in the store anymore");
+            }
+
+            if strict || fail_on_unreferenced {
+                let referenced = config.referenced(&store);
+
+                for name in store.keys().filter(|k| !referenced.contains(*k)).sorted() {
+                    found_error |= fail_on_unreferenced;
+                    warn!("{name} is in the store, but nothing uses it");
+                }
+            }
+
+            // purely informational, a big store still works
+            let size = Store::size_on_disk().unwrap_or(0);
+            if store.len() >= LARGE_STORE_VARIABLES || size >= LARGE_STORE_BYTES {
+                println!(
+                    "note: the store has {} variables ({} KiB), so loading projects may be slow. \
+                    run with --verbose to see whether the time goes to the keyring or decryption",
+                    store.len(),
+                    size / 1024
+                );
             }
 
             if found_error {
-                process::exit(1);
+                fail!("check", "the config has errors");
             } else {
                 println!("the config is correct!");
             }
         }
-        Commands::Init { shell } => {
+        Commands::Init {
+            shell,
+            with_completions,
+        } => {
             println!("{}", shell.init());
+
+            if with_completions {
+                println!("{}", shell.completions());
+            }
+        }
+        Commands::Setup => setup::run(),
+        Commands::Stats { json, record } => {
+            let stats = Stats::collect(&Config::read(), &Store::read());
+
+            match json {
+                true => println!("{}", stats.to_json()),
+                false => println!("{stats}"),
+            }
+
+            if let Some(path) = record {
+                stats.record(&path);
+            }
+        }
+        Commands::CompleteNames { prefix } => {
+            let store = Store::read();
+            let prefix = prefix.to_uppercase();
+
+            for name in store
+                .keys()
+                .chain(store.aliases().map(|(alias, _)| alias))
+                .filter(|name| name.starts_with(&prefix))
+                .sorted()
+            {
+                println!("{name}");
+            }
+        }
+        Commands::Selftest => {
+            if !selftest::run() {
+                fail!("selftest", "some checks failed");
+            }
         }
         Commands::Env { subcommand } => match subcommand {
             EnvSubcommand::Add {
@@ -134,46 +1049,194 @@ fn main() {
                 value,
                 overwrite,
             } => {
+                let _lock = Store::lock();
                 let mut store = Store::read();
                 let name = name.to_uppercase();
 
                 let is_used = store.get(&name).is_some();
-                match (is_used, overwrite) {
+                let added = match (is_used, overwrite) {
                     (false, _) => {
-                        store.add(name, &value);
+                        store.add(name.clone(), &value);
+                        true
                     }
                     (true, true) => {
                         eprintln!("Overwriting value for {}", name);
-                        store.add(name, &value);
+                        store.add(name.clone(), &value);
+                        true
                     }
                     (true, false) => {
                         eprintln!(
                             "Value for {} already exists. Use --overwrite to replace it",
                             name
                         );
+                        false
+                    }
+                };
+
+                store.save_to_disk();
+
+                // saving checked the store itself, this checks the value can be read back out
+                if added && store::verify_after_write() {
+                    match Store::read().get(&name).map(|v| v.decrypt()) {
+                        Some(Ok(variable)) if variable.value() == value => {}
+                        _ => store::verify_failed(format_args!(
+                            "{name} doesn't decrypt to the value that was added"
+                        )),
+                    }
+                }
+            }
+            EnvSubcommand::Import {
+                from_keyring,
+                accounts,
+                format,
+                overwrite,
+            } => {
+                let _lock = Store::lock();
+                let mut store = Store::read();
+                let mut found_error = false;
+
+                if let Some(from_keyring) = from_keyring {
+                    for account in accounts {
+                        let (account, name) = match account.split_once('=') {
+                            Some((account, name)) => (account.to_owned(), name.to_uppercase()),
+                            None => (account.clone(), account.to_uppercase()),
+                        };
+
+                        if store.contains(&name) && !overwrite {
+                            found_error = true;
+                            eprintln!(
+                                "cryptenv: value for {} already exists. Use --overwrite to replace it",
+                                name
+                            );
+                            continue;
+                        }
+
+                        let password =
+                            keyring::Entry::new(&from_keyring, &account).and_then(|entry| {
+                                store::keyring_call(entry, |entry| entry.get_password())
+                            });
+
+                        match password {
+                            Ok(mut password) => {
+                                store.add(name.clone(), &password);
+                                password.zeroize();
+                                println!("imported {from_keyring}/{account} as {name}");
+                            }
+                            Err(e) => {
+                                found_error = true;
+                                eprintln!("cryptenv: could not read {from_keyring}/{account}: {e}");
+                            }
+                        }
+                    }
+                }
+
+                if let Some(ImportFormat::Cryptenv) = format {
+                    let bundle: Bundle = serde_json::from_reader(std::io::stdin())
+                        .unwrap_or_else(|_| fail!("bundle", "stdin is not a cryptenv bundle"));
+
+                    let vars = bundle
+                        .open(&read_passphrase(false))
+                        .unwrap_or_else(|e| fail!("bundle", "could not open the bundle: {e}"));
+
+                    for (name, mut value) in vars.into_iter().sorted() {
+                        if store.contains(&name) && !overwrite {
+                            found_error = true;
+                            eprintln!(
+                                "cryptenv: value for {} already exists. Use --overwrite to replace it",
+                                name
+                            );
+                        } else {
+                            store.add(name.clone(), &value);
+                            println!("imported {name}");
+                        }
+
+                        value.zeroize();
                     }
                 }
 
                 store.save_to_disk();
+
+                if found_error {
+                    fail!("import", "some variables could not be imported");
+                }
             }
-            EnvSubcommand::Get { name } => {
+            EnvSubcommand::Get {
+                name,
+                encoding,
+                field,
+            } => {
                 let store = Store::read();
                 let name = name.to_uppercase();
 
-                let variable = store.get(&name).map(|v| v.decrypt()).unwrap_or_else(|| {
-                    eprintln!("cryptenv: variable {} not found", name);
-                    process::exit(1);
+                let variable = store
+                    .get(&name)
+                    .map(|v| v.decrypt())
+                    .unwrap_or_else(|| fail!("not_found", "variable {} not found", name));
+                let variable = variable.unwrap_or_else(|e| {
+                    fail(
+                        e.kind(),
+                        format_args!("could not decrypt {}: {}", name, e),
+                        e.hint(),
+                    )
                 });
 
-                println!("{}", variable.value());
+                let value = match field {
+                    Some(field) => {
+                        let json: serde_json::Map<String, serde_json::Value> =
+                            serde_json::from_str(variable.value()).unwrap_or_else(|_| {
+                                fail!("field", "{} is not a JSON object", name)
+                            });
+
+                        match json.get(&field) {
+                            Some(serde_json::Value::String(value)) => value.to_owned(),
+                            Some(value) => value.to_string(),
+                            None => {
+                                fail!("field", "{} has no field {}", name, field);
+                            }
+                        }
+                    }
+                    None => variable.value().to_owned(),
+                };
+
+                match encoding {
+                    Some(encoding) => println!("{}", encoding.encode(&value)),
+                    None => println!("{}", value),
+                }
             }
-            EnvSubcommand::List { decrypt } => {
+            EnvSubcommand::Alias { name, existing } => {
+                let _lock = Store::lock();
+                let mut store = Store::read();
+
+                if let Err(e) = store.alias(name.to_uppercase(), &existing.to_uppercase()) {
+                    fail!("alias", "{e}");
+                }
+
+                store.save_to_disk();
+            }
+            EnvSubcommand::List { decrypt, full } => {
                 let store = Store::read();
 
-                for (name, variable) in store.iter() {
-                    if decrypt {
-                        println!("{}={}", name, variable.decrypt().value());
-                    } else {
+                if decrypt {
+                    let decrypted = store.decrypt_all(store.keys());
+                    let mut found_error = false;
+
+                    // one broken variable shouldn't hide the rest of the store
+                    for (name, _) in store.iter() {
+                        match &decrypted[name] {
+                            Ok(variable) if full => println!("{}={}", name, variable.value()),
+                            Ok(variable) => println!("{}={}", name, summarize(variable.value())),
+                            Err(e) => {
+                                found_error = true;
+                                eprintln!("{}=<decrypt error: {}>", name, e);
+                            }
+                        }
+                    }
+
+                    if found_error {
+                        fail!("decrypt", "some variables could not be decrypted");
+                    }
+                } else {
+                    for (name, _) in store.iter() {
                         println!("{}", name);
                     }
                 }
@@ -181,31 +1244,174 @@ fn main() {
         },
 
         Commands::Project { subcommand } => match subcommand {
-            ProjectSubcommand::Load { shell } => {
+            ProjectSubcommand::Load {
+                shell,
+                overrides,
+                profile,
+                skip,
+                max_age,
+                show,
+                bundle,
+            } => {
                 let config = Config::read();
                 let store = Store::read();
-                let project = Project::get_from_cwd().unwrap_or_default();
+                let mut project = match &bundle {
+                    Some(name) => {
+                        let bundle = config
+                            .bundles()
+                            .get(name)
+                            .unwrap_or_else(|| fail!("not_found", "bundle {name} doesn't exist"));
 
-                println!("{}", config.unset(shell));
-                println!("{}", project.to_shell(&store, shell));
+                        let mut project = Project::default();
+                        project.merge_profile(bundle);
+                        project
+                    }
+                    None => Project::get_from_cwd()
+                        .or_else(|| config.fallback_project())
+                        .unwrap_or_default(),
+                };
+                let Some(shell) = shell.or(project.shell()) else {
+                    fail(
+                        "usage",
+                        "no shell given, and this project doesn't set one",
+                        Some(&format!(
+                            "pass one of {}, e.g. `cryptenv project load zsh`",
+                            Shell::all().iter().join(", ")
+                        )),
+                    );
+                };
+
+                if let Some(profile) = profile {
+                    project.retain_profile(&config, &profile);
+                }
+
+                // exporting over a readonly variable would fail halfway through the script
+                if project.is_readonly() {
+                    let readonly = overrides
+                        .iter()
+                        .find(|(key, _)| project.keys().any(|k| k == key));
+                    if let Some((key, _)) = readonly {
+                        fail!(
+                            "usage",
+                            "{key} is readonly in this project, so --set can't change it"
+                        );
+                    }
+                }
+
+                // skipped before resolving, so nothing is decrypted (or run) for them
+                let skipped = project.skip(&skip);
+                for key in &skipped {
+                    verbose!("skipped {key}");
+                }
+
+                for name in project.store_variables().unique().sorted() {
+                    let max_age = config.rotation().max_age_days(name, max_age);
+
+                    if let (Some(max_age), Some(age)) = (max_age, store.age_days(name)) {
+                        if age > max_age {
+                            warn!("{name} is {age} days old; consider rotating");
+                        }
+                    }
+                }
+
+                // the script is built up front and printed in one go, so nothing half-finished
+                // is eval'd if resolving a variable fails. stdout is only ever the script
+                let mut script = String::new();
+                // a bundle is loaded on top of whatever is already set
+                if bundle.is_none() {
+                    if show {
+                        script.push_str("# unset the variables of every project\n");
+                    }
+                    script.push_str(&config.unset(shell, &store, &skipped));
+                    script.push('\n');
+                }
+
+                if show {
+                    script.push_str("# set this project's variables\n");
+                    script.push_str(&project.to_shell_masked(&store, shell));
+                } else {
+                    script.push_str(&project.to_shell(&store, shell));
+                }
+                script.push('\n');
+
+                if show && !overrides.is_empty() {
+                    script.push_str("# --set overrides\n");
+                }
+                for (key, value) in overrides {
+                    let value = match show {
+                        true => store::Redacted(&value).to_string(),
+                        false => value,
+                    };
+
+                    script.push_str(&shell.export(&key, &value));
+                    script.push('\n');
+                }
+
+                match show {
+                    true => eprint!("{script}"),
+                    false => print!("{script}"),
+                }
             }
             ProjectSubcommand::Name => {
-                let dir = Project::get_project_dir(&Config::read());
+                let config = Config::read();
+
+                match Project::detect(&config) {
+                    Detection::Project(name) => {
+                        println!("{name}");
 
-                match dir {
-                    Some(d) => {
-                        println!("{d}");
+                        if !config.projects().contains_key(&name) {
+                            warn!("{name} isn't a project in the config, so nothing is loaded");
+                        }
                     }
-                    _ => {
-                        process::exit(1);
+                    Detection::Root(root) => {
+                        fail(
+                            "not_found",
+                            format_args!(
+                                "{} is a tracked dir, but no project is at this depth",
+                                root.display()
+                            ),
+                            Some("projects are the directories directly inside it"),
+                        );
+                    }
+                    Detection::Outside => {
+                        let dirs = config.dirs().iter().map(|d| d.display()).join(", ");
+                        let hint = match dirs.is_empty() {
+                            true => "there are no dirs in the config".to_owned(),
+                            false => format!("the tracked dirs are {dirs}"),
+                        };
+
+                        fail("not_found", "not under any tracked dir", Some(&hint));
                     }
                 }
             }
-            ProjectSubcommand::List { project } => {
-                let p = Project::get_by_name(&project);
-                match p {
-                    Some(project) => {
-                        for v in project.variables() {
+            ProjectSubcommand::List { project, tree } => {
+                let config = Config::read();
+                let project = project.or_else(|| Project::get_project_dir(&config));
+
+                if tree {
+                    let store = Store::read();
+                    let names = match &project {
+                        Some(name) => vec![name],
+                        None => config.projects().keys().sorted().collect(),
+                    };
+
+                    for name in names {
+                        match config.project(name) {
+                            Some(p) => print!("{}", p.tree(name, &store)),
+                            None => eprintln!("project {name} was not find"),
+                        }
+                    }
+
+                    return;
+                }
+
+                let Some(project) = project else {
+                    fail!("not_found", "not in a project directory");
+                };
+
+                match config.project(&project) {
+                    Some(p) => {
+                        for v in p.variables() {
                             println!("{}", v);
                         }
                     }
@@ -214,19 +1420,122 @@ fn main() {
                     }
                 }
             }
-            ProjectSubcommand::Export { project } => {
-                let p = Project::get_by_name(&project);
+            ProjectSubcommand::Export {
+                project,
+                all: _,
+                out_dir,
+                only_changed,
+                names_only,
+                example,
+                profile,
+                format,
+                prefix,
+                rename,
+                to_fifo,
+                fd,
+            } => {
+                let config = Config::read();
                 let store = Store::read();
+                let rename: HashMap<_, _> = rename.into_iter().collect();
+                let handoff = to_fifo.or(fd.map(|fd| PathBuf::from(format!("/dev/fd/{fd}"))));
+                let mut passphrase = None;
 
-                match p {
-                    Some(project) => {
-                        for (k, v) in project.into_inner() {
-                            println!("{}={}", k, store.get(&v).unwrap().decrypt().value());
-                        }
+                // clap makes sure there's a project unless --all is passed
+                let names = match project {
+                    Some(project) => vec![project],
+                    None => config.projects().keys().sorted().cloned().collect(),
+                };
+
+                for name in names {
+                    let Some(mut project) = config.project(&name) else {
+                        eprintln!("project {name} was not find");
+                        continue;
+                    };
+
+                    if let Some(profile) = &profile {
+                        project.retain_profile(&config, profile);
                     }
-                    None => {
-                        eprintln!("project {project} was not find");
+                    project.rename_keys(&prefix, &rename);
+
+                    let output = if example {
+                        project.to_example()
+                    } else if names_only {
+                        project.keys().sorted().map(|k| format!("{k}=\n")).collect()
+                    } else {
+                        format.render(&project, &store, &mut passphrase)
+                    };
+
+                    if let Some(handoff) = &handoff {
+                        write_handoff(handoff, output);
+                        continue;
                     }
+
+                    let Some(out_dir) = &out_dir else {
+                        print!("{output}");
+                        continue;
+                    };
+
+                    let extension = if example {
+                        "env.example"
+                    } else if names_only {
+                        "env"
+                    } else {
+                        format.extension()
+                    };
+                    let path = out_dir.join(format!("{name}.{extension}"));
+
+                    match write_if_changed(&path, &output, only_changed) {
+                        true => println!("wrote {}", path.display()),
+                        false => println!("unchanged {}", path.display()),
+                    }
+                }
+            }
+        },
+
+        Commands::Key { subcommand } => match subcommand {
+            KeySubcommand::Fingerprint => match store::fingerprint() {
+                Some(fingerprint) => println!("{fingerprint}"),
+                None => {
+                    fail!("key", "no key found");
+                }
+            },
+            KeySubcommand::Reset { wipe_store } => {
+                if !wipe_store {
+                    fail(
+                        "usage",
+                        "resetting the key makes every value in the store undecryptable, so the \
+                        store is deleted too",
+                        Some("pass --wipe-store if that's what you want"),
+                    );
+                }
+
+                let store = Store::read();
+                eprintln!(
+                    "this deletes the key and all {} variables in the store. they can't be recovered",
+                    store.len()
+                );
+                eprint!("type \"{RESET_CONFIRMATION}\" to continue: ");
+
+                let mut answer = String::new();
+                std::io::stdin()
+                    .read_line(&mut answer)
+                    .expect("Could not read from stdin");
+
+                if answer.trim() != RESET_CONFIRMATION {
+                    fail!("usage", "not resetting");
+                }
+
+                let _lock = Store::lock();
+                if let Err(e) = store::delete_key() {
+                    fail!("keyring", "could not delete the key from the keyring: {e}");
+                }
+                Store::delete_from_disk();
+
+                println!("deleted the key and the store. a new key will be made the next time you add a variable");
+                if std::env::var("CRYPTENV_KEY").is_ok() {
+                    warn!("CRYPTENV_KEY is set, so cryptenv will keep using that key until you unset it");
+                } else if store::key_command().is_some() {
+                    warn!("there's a key command, so cryptenv will keep using the key it prints until you remove it");
                 }
             }
         },
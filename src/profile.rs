@@ -0,0 +1,25 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// a reusable group of variables that projects can pull in with `profiles = ["name"]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    vars: HashMap<String, String>,
+    /// descriptions of the variables, used as comments by `project export --example`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    notes: HashMap<String, String>,
+}
+
+impl Profile {
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(String::as_str)
+    }
+    pub fn note(&self, key: &str) -> Option<&str> {
+        self.notes.get(key).map(String::as_str)
+    }
+    /// iterate over the (export name, store variable) pairs of this profile
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
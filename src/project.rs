@@ -1,29 +1,263 @@
-use std::{collections::HashMap, fmt::Write, process};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    process,
+};
 
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use crate::{config::Config, store::Store, Shell};
+use crate::{
+    config::Config,
+    store::{DecryptError, DecryptedVariable, Redacted, Store},
+    Profile, Shell,
+};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Project {
+    #[serde(default)]
     vars: HashMap<String, String>,
+    /// also export every store variable starting with this prefix, without it. e.g. with
+    /// `strip_prefix = "PROJ_"`, `PROJ_DATABASE_URL` is exported as `DATABASE_URL`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strip_prefix: Option<String>,
+    /// the profiles whose variables this project also gets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    profiles: Vec<String>,
+    /// descriptions of the variables, used as comments by `project export --example`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    notes: HashMap<String, String>,
+    /// export the variables as readonly in shells that support it, so they can't be changed for
+    /// the rest of the session
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    readonly: bool,
+    /// variables `project load` leaves alone, e.g. because other tooling sets them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skip: Vec<String>,
+    /// the shell `project load` prints the script for when it isn't given one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<Shell>,
 }
 
+/// where the current directory is relative to the config's `dirs`, so `project name` can say why
+/// there's no project
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Detection {
+    /// inside the project directory with this name. it isn't necessarily in the config
+    Project(String),
+    /// directly in one of the `dirs`, above any project
+    Root(PathBuf),
+    /// not under any of the `dirs`
+    Outside,
+}
+
+/// where the value of a project variable comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source<'a> {
+    /// a variable in the cryptenv store. this is the default
+    Store(&'a str),
+    /// `env:NAME`, passed through from the environment cryptenv is run in. this is read as-is, it
+    /// isn't interpolated or looked up in the store
+    Env(&'a str),
+    /// `cmd:COMMAND`, the output of running COMMAND with `sh -c`, for secrets that live in another
+    /// password manager or secret backend. one trailing newline is removed
+    Cmd(&'a str),
+}
+
+/// why a project variable couldn't be resolved. none of these contain the value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// the store variable with this name couldn't be decrypted
+    Decrypt(String, DecryptError),
+    /// there's no store variable with this name
+    NotFound(String),
+    /// the `env:` variable with this name isn't set
+    EnvNotSet(String),
+    /// a `cmd:` couldn't be run, failed, or didn't print utf8. holds the whole message
+    Cmd(String),
+}
+
+impl<'a> Source<'a> {
+    /// values without a known `scheme:` prefix are store variables
+    pub fn parse(value: &'a str) -> Self {
+        if let Some(name) = value.strip_prefix("env:") {
+            Source::Env(name)
+        } else if let Some(command) = value.strip_prefix("cmd:") {
+            Source::Cmd(command)
+        } else {
+            Source::Store(value)
+        }
+    }
+
+    /// get the value from wherever it comes from. store variables have to be in `decrypted`
+    fn resolve(
+        &self,
+        decrypted: &HashMap<&str, Result<DecryptedVariable, DecryptError>>,
+    ) -> Result<String, ResolveError> {
+        match *self {
+            Source::Store(name) => match decrypted.get(name) {
+                Some(Ok(variable)) => Ok(variable.value().to_owned()),
+                Some(Err(e)) => Err(ResolveError::Decrypt(name.to_owned(), *e)),
+                None => Err(ResolveError::NotFound(name.to_owned())),
+            },
+            Source::Env(name) => {
+                std::env::var(name).map_err(|_| ResolveError::EnvNotSet(name.to_owned()))
+            }
+            Source::Cmd(command) => {
+                let output = process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(process::Stdio::null())
+                    .stderr(process::Stdio::inherit())
+                    .output()
+                    .map_err(|e| {
+                        ResolveError::Cmd(format!("could not run `{}`: {}", command, e))
+                    })?;
+
+                if !output.status.success() {
+                    return Err(ResolveError::Cmd(format!(
+                        "`{}` failed with {}",
+                        command, output.status
+                    )));
+                }
+
+                let mut value = String::from_utf8(output.stdout).map_err(|_| {
+                    ResolveError::Cmd(format!("the output of `{}` is not valid utf8", command))
+                })?;
+                if value.ends_with('\n') {
+                    value.pop();
+                }
+
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::Decrypt(name, e) => write!(f, "could not decrypt {}: {}", name, e),
+            ResolveError::NotFound(name) => write!(f, "variable {} not found", name),
+            ResolveError::EnvNotSet(name) => write!(f, "environment variable {} is not set", name),
+            ResolveError::Cmd(message) => f.write_str(message),
+        }
+    }
+}
+
+impl ResolveError {
+    /// the `kind` of this error in `--error-format json`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ResolveError::Decrypt(_, e) => e.kind(),
+            ResolveError::NotFound(_) => "not_found",
+            ResolveError::EnvNotSet(_) | ResolveError::Cmd(_) => "resolve",
+        }
+    }
+
+    /// what the user can do about this error, if there's anything
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ResolveError::Decrypt(_, e) => e.hint(),
+            ResolveError::NotFound(_) => Some("add it with `cryptenv env add <name> <value>`"),
+            ResolveError::EnvNotSet(_) | ResolveError::Cmd(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
 impl Project {
     pub fn to_shell(&self, store: &Store, shell: Shell) -> String {
+        self.render(store, |key, value| self.export(shell, key, value))
+    }
+
+    /// `to_shell` with every value replaced by `<redacted>`, for showing the script without
+    /// showing the secrets. the values are still resolved, so errors show up the same way
+    pub fn to_shell_masked(&self, store: &Store, shell: Shell) -> String {
+        self.render(store, |key, value| {
+            self.export(shell, key, &Redacted(value).to_string())
+        })
+    }
+
+    fn export(&self, shell: Shell, key: &str, value: &str) -> String {
+        match self.readonly {
+            true => shell.export_readonly(key, value),
+            false => shell.export(key, value),
+        }
+    }
+
+    /// the extension point for custom exporters: resolves every variable like `resolve`, and
+    /// formats each (name, value) pair with `format`, one line each, sorted by name. `iter` gives
+    /// the names and where their values come from without resolving anything
+    pub fn render(&self, store: &Store, format: impl Fn(&str, &str) -> String) -> String {
+        let mut output = String::new();
+
+        for (key, value) in self.resolve(store) {
+            writeln!(output, "{}", format(key, &value)).expect("writing to string succeeded");
+        }
+
+        output
+    }
+
+    /// get the value of every variable in this project, sorted by name. exits if any variable
+    /// can't be resolved, e.g. it isn't in the store or its command fails
+    pub fn resolve(&self, store: &Store) -> Vec<(&str, String)> {
+        let decrypted = store.decrypt_all(self.store_variables());
+
+        self.iter()
+            .sorted()
+            .map(|(key, value)| {
+                let value = Source::parse(value)
+                    .resolve(&decrypted)
+                    .unwrap_or_else(|e| crate::fail(e.kind(), &e, e.hint()));
+
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// a .env.example style template: every variable with an empty value, sorted, with its note
+    /// (if it has one) as a comment above it. never reads the store
+    pub fn to_example(&self) -> String {
         let mut output = String::new();
 
-        for (key, value) in &self.vars {
-            let variable = store.get(value).map(|v| v.decrypt()).unwrap_or_else(|| {
-                eprintln!("cryptenv: variable {} not found", value);
-                process::exit(1);
-            });
+        for key in self.keys().sorted() {
+            if let Some(note) = self.notes.get(key) {
+                for line in note.lines() {
+                    writeln!(output, "# {line}").expect("writing to string succeeded");
+                }
+            }
+
+            writeln!(output, "{key}=").expect("writing to string succeeded");
+        }
 
-            let res = match shell {
-                Shell::Zsh => writeln!(output, "export {}={}", key, variable.value()),
-                Shell::Fish => writeln!(output, "set -gx {} {};", key, variable.value()),
+        output
+    }
+
+    /// render this project as a tree of its variables and where they come from, marking the
+    /// variables that are missing from the store
+    pub fn tree(&self, name: &str, store: &Store) -> String {
+        let mut output = match self.profiles.is_empty() {
+            true => format!("{name}\n"),
+            false => format!("{name} (profiles: {})\n", self.profiles.join(", ")),
+        };
+        let vars: Vec<_> = self.iter().sorted().collect();
+
+        for (i, (key, value)) in vars.iter().enumerate() {
+            let branch = if i + 1 == vars.len() {
+                "└──"
+            } else {
+                "├──"
             };
-            res.expect("writing to string succeeded");
+            let missing = match Source::parse(value) {
+                Source::Store(name) if !store.contains(name) => " (missing from store)",
+                _ => "",
+            };
+
+            writeln!(output, "{branch} {key} → {value}{missing}")
+                .expect("writing to string succeeded");
         }
 
         output
@@ -31,37 +265,147 @@ impl Project {
 
     /// get the project in the current directory
     pub fn get_from_cwd() -> Option<Self> {
-        let mut config = Config::read();
+        let config = Config::read();
 
         let Some(project_dir) = Self::get_project_dir(&config) else {
             return Default::default();
         };
 
-        // it's fine to remove the "project" from the config because config is dropped at the end
-        // of this function anyways
-        config.projects_mut().remove(&project_dir)
+        config.project(&project_dir)
     }
 
     pub fn get_project_dir(config: &Config) -> Option<String> {
+        match Self::detect(config) {
+            Detection::Project(name) => Some(name),
+            Detection::Root(_) | Detection::Outside => None,
+        }
+    }
+
+    /// where the current directory is relative to the config's `dirs`
+    pub fn detect(config: &Config) -> Detection {
         let current_dir = std::env::current_dir().unwrap();
         let dirs = config.dirs();
+        let case_sensitive = config.case_sensitive();
 
         for dir in dirs.into_iter() {
-            if current_dir.starts_with(&dir) {
+            if starts_with(&current_dir, &dir, case_sensitive) {
                 let original_len = dir.components().collect::<Vec<_>>().len();
-                let parent = current_dir.components().nth(original_len)?;
+                let Some(parent) = current_dir.components().nth(original_len) else {
+                    return Detection::Root(dir);
+                };
 
-                return Some(parent.as_os_str().to_str().unwrap().to_string());
+                return Detection::Project(parent.as_os_str().to_str().unwrap().to_string());
             }
         }
 
-        None
+        Detection::Outside
     }
 
-    pub fn get_by_name(name: &str) -> Option<Self> {
-        let config = Config::read();
+    /// add a variable for every store variable matching `strip_prefix`, unless the project already
+    /// has a variable with that name
+    pub fn expand_prefix(&mut self, store: &Store) {
+        let Some(prefix) = self.strip_prefix.as_deref().filter(|p| !p.is_empty()) else {
+            return;
+        };
+
+        for name in store.keys() {
+            let Some(key) = name.strip_prefix(prefix).filter(|key| !key.is_empty()) else {
+                continue;
+            };
+
+            self.vars
+                .entry(key.to_owned())
+                .or_insert_with(|| name.to_owned());
+        }
+    }
 
-        config.projects().get(name).cloned()
+    pub fn strip_prefix(&self) -> Option<&str> {
+        self.strip_prefix.as_deref()
+    }
+
+    /// add the variables (and their notes) from `profile` that this project doesn't already have
+    pub fn merge_profile(&mut self, profile: &Profile) {
+        for (key, value) in profile.iter() {
+            if self.vars.contains_key(key) {
+                continue;
+            }
+
+            self.vars.insert(key.to_owned(), value.to_owned());
+            if let Some(note) = profile.note(key) {
+                self.notes.insert(key.to_owned(), note.to_owned());
+            }
+        }
+    }
+
+    /// only keep the variables that `profile` gives this project. exits if the project doesn't
+    /// use that profile
+    pub fn retain_profile(&mut self, config: &Config, profile: &str) {
+        let found = config
+            .get_profile(profile)
+            .filter(|_| self.profiles.iter().any(|p| p == profile));
+
+        let Some(found) = found else {
+            fail!("usage", "this project doesn't use the profile {profile}");
+        };
+
+        let keys: Vec<_> = found.keys().collect();
+        self.vars.retain(|key, _| keys.contains(&key.as_str()));
+    }
+
+    /// rename the exported variables: keys in `renames` get their new name, and every other key
+    /// gets `prefix` in front of it. the store and config are untouched. exits if two variables
+    /// would end up with the same name
+    pub fn rename_keys(&mut self, prefix: &str, renames: &HashMap<String, String>) {
+        let rename = |key: &str| match renames.get(key) {
+            Some(new) => new.clone(),
+            None => format!("{prefix}{key}"),
+        };
+
+        let mut vars = HashMap::with_capacity(self.vars.len());
+        for (key, value) in self.vars.drain() {
+            let new = rename(&key);
+            if vars.insert(new.clone(), value).is_some() {
+                fail!("usage", "more than one variable would be exported as {new}");
+            }
+        }
+
+        self.vars = vars;
+        self.notes = self
+            .notes
+            .drain()
+            .map(|(key, note)| (rename(&key), note))
+            .collect();
+    }
+
+    /// remove the variables in this project's `skip` list, and in `extra`. returns every skipped
+    /// name, sorted, whether or not the project had it
+    pub fn skip(&mut self, extra: &[String]) -> Vec<String> {
+        let skipped: Vec<_> = self
+            .skip
+            .iter()
+            .chain(extra)
+            .unique()
+            .cloned()
+            .sorted()
+            .collect();
+
+        for key in &skipped {
+            self.vars.remove(key);
+        }
+
+        skipped
+    }
+
+    pub fn shell(&self) -> Option<Shell> {
+        self.shell
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        self.profiles.iter().map(String::as_str)
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &str> {
@@ -70,7 +414,30 @@ impl Project {
     pub fn variables(&self) -> impl Iterator<Item = &str> {
         self.vars.values().map(String::as_str)
     }
-    pub fn into_inner(self) -> HashMap<String, String> {
-        self.vars
+    /// the variables this project reads from the store, skipping `env:` and `cmd:` variables
+    pub fn store_variables(&self) -> impl Iterator<Item = &str> {
+        self.variables().filter_map(|v| match Source::parse(v) {
+            Source::Store(name) => Some(name),
+            Source::Env(_) | Source::Cmd(_) => None,
+        })
+    }
+    /// iterate over the (export name, store variable) pairs of this project
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// `Path::starts_with`, optionally ignoring the case of each component
+fn starts_with(path: &Path, base: &Path, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        return path.starts_with(base);
     }
+
+    let mut components = path.components();
+    base.components().all(|base| {
+        components.next().is_some_and(|component| {
+            let (a, b) = (component.as_os_str(), base.as_os_str());
+            a == b || a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+        })
+    })
 }
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// the `[rotation]` table: how old store variables can get before `project load` reminds you to
+/// rotate them. purely advisory, nothing is ever blocked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rotation {
+    /// the max age of every variable that isn't in a tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_age_days: Option<u64>,
+    /// classes of secrets with their own max age, e.g. `[rotation.tags.payments]`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<String, Tag>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+    max_age_days: u64,
+    /// the store variables with this tag
+    variables: Vec<String>,
+}
+
+impl Rotation {
+    /// the max age in days of the store variable `name`. when it has more than one tag, the
+    /// shortest max age wins. `default` replaces `max_age_days` for untagged variables
+    pub fn max_age_days(&self, name: &str, default: Option<u64>) -> Option<u64> {
+        let tagged = self
+            .tags
+            .values()
+            .filter(|tag| tag.variables.iter().any(|v| v == name))
+            .map(|tag| tag.max_age_days)
+            .min();
+
+        tagged.or(default).or(self.max_age_days)
+    }
+
+    /// merge in the rotation policy of another config file. its tags and max age win
+    pub fn merge(&mut self, other: Rotation) {
+        if other.max_age_days.is_some() {
+            self.max_age_days = other.max_age_days;
+        }
+
+        self.tags.extend(other.tags);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fs, process};
+
+use aes_gcm::{aead::OsRng, Aes256Gcm, KeyInit};
+use keyring::Entry;
+
+use crate::{
+    store::{self, DecryptError},
+    Store,
+};
+
+/// the value every check encrypts and expects back
+const VALUE: &str = "cryptenv selftest";
+
+/// one check: `Err` says what went wrong
+type Check = fn() -> Result<(), String>;
+
+/// the `cryptenv selftest` command. checks that encryption, the keyring, and reading and writing
+/// a store file work, using a throwaway key, keyring entry and directory so the real store and
+/// key are never touched. returns whether every check passed
+pub fn run() -> bool {
+    let checks: [(&str, Check); 4] = [
+        ("encrypt/decrypt round trip", round_trip),
+        ("envelope round trip", envelope),
+        ("keyring write/read", keyring),
+        ("store file write/read", store_file),
+    ];
+
+    let mut passed = true;
+    for (name, check) in checks {
+        match check() {
+            Ok(()) => println!("pass  {name}"),
+            Err(e) => {
+                passed = false;
+                println!("FAIL  {name}: {e}");
+            }
+        }
+    }
+
+    // there's nothing to fall back to: without a keyring, the key has to come from CRYPTENV_KEY
+    println!("skip  key file fallback: cryptenv doesn't have one, use CRYPTENV_KEY instead");
+
+    passed
+}
+
+fn round_trip() -> Result<(), String> {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let encrypted = store::encrypt_with(&key, VALUE);
+
+    match store::decrypt_with(&key, &encrypted) {
+        Ok(value) if value == VALUE => {}
+        Ok(_) => return Err("decrypted a different value than was encrypted".to_owned()),
+        Err(e) => return Err(e.to_string()),
+    }
+
+    let other = Aes256Gcm::generate_key(&mut OsRng);
+    match store::decrypt_with(&other, &encrypted) {
+        Err(DecryptError::WrongKey) => Ok(()),
+        _ => Err("a different key could decrypt the value".to_owned()),
+    }
+}
+
+fn envelope() -> Result<(), String> {
+    let keys = [
+        Aes256Gcm::generate_key(&mut OsRng),
+        Aes256Gcm::generate_key(&mut OsRng),
+    ];
+    let encrypted = store::encrypt_envelope(&keys, VALUE);
+
+    for key in &keys {
+        match store::decrypt_with(key, &encrypted) {
+            Ok(value) if value == VALUE => {}
+            Ok(_) => return Err("decrypted a different value than was encrypted".to_owned()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    let other = Aes256Gcm::generate_key(&mut OsRng);
+    match store::decrypt_with(&other, &encrypted) {
+        Err(DecryptError::WrongKey) => Ok(()),
+        _ => Err("a key it wasn't encrypted with could decrypt the value".to_owned()),
+    }
+}
+
+fn keyring() -> Result<(), String> {
+    let entry = Entry::new("cryptenv", "selftest").map_err(|e| e.to_string())?;
+    let secret = Aes256Gcm::generate_key(&mut OsRng).to_vec();
+
+    store::keyring_call(entry, move |entry| {
+        entry
+            .set_secret(&secret)
+            .map_err(|e| format!("could not write: {e}"))?;
+
+        let read = entry.get_secret();
+        let deleted = entry.delete_credential();
+
+        match read {
+            Ok(read) if read == secret => {}
+            Ok(_) => return Err("read back a different secret than was written".to_owned()),
+            Err(e) => return Err(format!("could not read: {e}")),
+        }
+
+        deleted.map_err(|e| format!("could not delete the test entry: {e}"))
+    })
+}
+
+fn store_file() -> Result<(), String> {
+    let dir = std::env::temp_dir().join(format!("cryptenv-selftest-{}", process::id()));
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+    let result = (|| {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let path = dir.join("store.json");
+
+        let mut store = Store::default();
+        store.add_with(&key, "SELFTEST".to_owned(), VALUE);
+        let json = serde_json::to_string(&store).map_err(|e| e.to_string())?;
+        fs::write(&path, json).map_err(|e| format!("could not write: {e}"))?;
+
+        let json = fs::read_to_string(&path).map_err(|e| format!("could not read: {e}"))?;
+        let store: Store = serde_json::from_str(&json).map_err(|e| e.to_string())?;
+
+        let variable = store.get("SELFTEST").ok_or("the variable wasn't saved")?;
+        let result = match variable.decrypt_with(&key) {
+            Ok(decrypted) if decrypted.value() == VALUE => Ok(()),
+            Ok(_) => Err("read back a different value than was written".to_owned()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        result
+    })();
+
+    let _ = fs::remove_dir_all(&dir);
+
+    result
+}
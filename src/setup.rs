@@ -0,0 +1,128 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use itertools::Itertools;
+
+use crate::{store, Config, Shell};
+
+/// the `cryptenv setup` wizard. every step asks before doing anything, so running it again on an
+/// already set up machine is safe
+pub fn run() {
+    let path = Config::get_path();
+
+    if path.exists() {
+        println!("a config already exists at {}", path.display());
+
+        if confirm("back it up and create a new one?") {
+            write_config();
+        } else {
+            println!("keeping the existing config");
+        }
+    } else {
+        write_config();
+    }
+
+    if confirm("make sure the encryption key exists (creating it in your keyring if needed)?") {
+        store::init_key();
+        println!("the key is ready");
+    }
+
+    let default_shell = std::env::var("SHELL")
+        .ok()
+        .and_then(|shell| shell.rsplit('/').next().map(str::to_owned))
+        .and_then(|shell| Shell::from_str(&shell, true).ok())
+        .unwrap_or(Shell::Zsh);
+
+    let shell = loop {
+        let answer = prompt(&format!("which shell do you use? [{default_shell}]"));
+
+        if answer.is_empty() {
+            break default_shell;
+        }
+
+        match Shell::from_str(&answer, true) {
+            Ok(shell) => break shell,
+            Err(_) => println!("cryptenv supports {}", Shell::all().iter().join(", ")),
+        }
+    };
+
+    let (rc, line) = shell.rc_line();
+    println!("\nadd this to your {rc} to load projects when you cd into them:\n\n{line}\n");
+}
+
+/// asks for the directories projects live in and writes a config with them
+fn write_config() {
+    println!("which directories do your projects live in? (one per line, empty line to finish)");
+
+    let mut dirs = Vec::new();
+    loop {
+        let dir = prompt(">");
+
+        if dir.is_empty() {
+            break;
+        }
+
+        dirs.push(dir);
+    }
+
+    let config = Config::new(dirs);
+    if confirm(&format!(
+        "write the config to {}?",
+        Config::get_path().display()
+    )) {
+        let path = Config::get_path();
+        if path.exists() {
+            let backup = backup(&path);
+            println!("moved the old config to {}", backup.display());
+        }
+
+        config.save_to_disk();
+        println!("wrote the config. add projects to it under [projects.<name>.vars]");
+    }
+}
+
+/// move `path` to `path.bak`, or `path.<timestamp>.bak` if there's already a backup, so an older
+/// backup is never overwritten. returns where it went
+fn backup(path: &Path) -> PathBuf {
+    let mut backup = path.with_extension("toml.bak");
+    if backup.exists() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        backup = path.with_extension(format!("toml.{now}.bak"));
+    }
+
+    if backup.exists() {
+        fail!(
+            "setup",
+            "{} already exists, not backing up over it",
+            backup.display()
+        );
+    }
+    std::fs::rename(path, &backup).expect("Could not back up the config");
+
+    backup
+}
+
+fn prompt(question: &str) -> String {
+    print!("{question} ");
+    io::stdout().flush().expect("Could not flush stdout");
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .expect("Could not read from stdin");
+
+    answer.trim().to_owned()
+}
+
+fn confirm(question: &str) -> bool {
+    matches!(
+        prompt(&format!("{question} [y/N]")).to_lowercase().as_str(),
+        "y" | "yes"
+    )
+}
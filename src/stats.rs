@@ -0,0 +1,113 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{Config, Source, Store};
+
+/// counts describing the store and config, for `cryptenv stats`. never contains a name or value
+/// from the store, only how many there are
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    /// seconds since the unix epoch, so recorded stats can be lined up over time
+    timestamp: u64,
+    variables: usize,
+    aliases: usize,
+    dangling_aliases: usize,
+    store_bytes: u64,
+    projects: usize,
+    profiles: usize,
+    /// project and profile variables read from the store
+    store_references: usize,
+    /// project and profile variables passed through with `env:`
+    env_references: usize,
+    /// project and profile variables that run a `cmd:`
+    cmd_references: usize,
+    /// store variables that no project, profile or bundle uses
+    unreferenced: usize,
+}
+
+impl Stats {
+    pub fn collect(config: &Config, store: &Store) -> Self {
+        let sources = config
+            .projects()
+            .values()
+            .flat_map(|project| project.variables())
+            .chain(
+                config
+                    .profiles()
+                    .values()
+                    .flat_map(|profile| profile.iter().map(|(_, v)| v)),
+            )
+            .map(Source::parse)
+            .collect_vec();
+
+        let used = config.referenced(store);
+
+        Stats {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            variables: store.len(),
+            aliases: store.aliases().count(),
+            dangling_aliases: store.dangling_aliases().count(),
+            store_bytes: Store::size_on_disk().unwrap_or(0),
+            projects: config.projects().len(),
+            profiles: config.profiles().len(),
+            store_references: sources
+                .iter()
+                .filter(|s| matches!(s, Source::Store(_)))
+                .count(),
+            env_references: sources
+                .iter()
+                .filter(|s| matches!(s, Source::Env(_)))
+                .count(),
+            cmd_references: sources
+                .iter()
+                .filter(|s| matches!(s, Source::Cmd(_)))
+                .count(),
+            unreferenced: store.keys().filter(|name| !used.contains(*name)).count(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize stats")
+    }
+
+    /// append these stats to `path` as one line of JSON
+    pub fn record(&self, path: &Path) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Could not open metrics file");
+
+        writeln!(file, "{}", self.to_json()).expect("Could not write metrics file");
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "variables: {}", self.variables)?;
+        writeln!(
+            f,
+            "aliases: {} ({} dangling)",
+            self.aliases, self.dangling_aliases
+        )?;
+        writeln!(f, "store size: {} bytes", self.store_bytes)?;
+        writeln!(f, "projects: {}", self.projects)?;
+        writeln!(f, "profiles: {}", self.profiles)?;
+        writeln!(
+            f,
+            "references: {} store, {} env, {} cmd",
+            self.store_references, self.env_references, self.cmd_references
+        )?;
+        write!(f, "unreferenced variables: {}", self.unreferenced)
+    }
+}
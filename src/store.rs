@@ -1,17 +1,174 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::{File, TryLockError},
+    path::PathBuf,
+    process,
+    sync::{mpsc, OnceLock},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use aes_gcm::{
     aead::{Aead, OsRng},
     AeadCore, Aes256Gcm, Key, KeyInit,
 };
 use keyring::Entry;
+
+use crate::combined::{self, Combined};
 use serde::{Deserialize, Serialize};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// the environment (e.g. dev or prod) set with `--env`/`CRYPTENV_ENV`. each environment has its
+/// own store file and key
+static ENVIRONMENT: OnceLock<String> = OnceLock::new();
+
+/// partition the store by `env`. must be called before anything reads the store or key
+pub fn set_environment(env: String) {
+    if env.is_empty()
+        || !env
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        fail!(
+            "usage",
+            "the environment name can only have letters, numbers, - and _"
+        );
+    }
+
+    ENVIRONMENT.set(env).expect("environment is only set once");
+}
+
+/// the start of values encrypted with `CRYPTENV_ENVELOPE_KEYS`. the value is encrypted with a random
+/// data key, followed by a copy of the data key encrypted with each key, all in base64 and separated
+/// by `.`. values without a prefix are encrypted with the cryptenv key directly
+const ENVELOPE_PREFIX: &str = "envelope.v1:";
+
+/// how long a keyring call can take before we give up, used when `--timeout` isn't passed
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// the bound on keyring calls set with `--timeout`/`CRYPTENV_TIMEOUT`
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// bound every keyring call by `secs` seconds. must be called before anything touches the key
+pub fn set_timeout(secs: u64) {
+    TIMEOUT
+        .set(Duration::from_secs(secs))
+        .expect("timeout is only set once");
+}
+
+/// how long to wait for the store lock when `--store-lock-timeout` isn't passed
+pub const DEFAULT_LOCK_TIMEOUT_SECS: u64 = 5;
+
+/// the exit code when the store stays locked for too long. EX_TEMPFAIL from sysexits.h, since
+/// trying again later should work
+pub const LOCKED_EXIT_CODE: i32 = 75;
+
+/// the key command, set once at startup with `set_key_command`
+/// an error means the config couldn't be read, so whether there's a key command isn't known
+static KEY_COMMAND: OnceLock<Result<Option<String>, String>> = OnceLock::new();
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// the key the key command printed, so it's only run once
+static COMMAND_KEY: OnceLock<Zeroizing<[u8; 32]>> = OnceLock::new();
+
+/// the bound on waiting for the store lock, set with `--store-lock-timeout`
+static LOCK_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+pub fn set_lock_timeout(secs: u64) {
+    LOCK_TIMEOUT
+        .set(Duration::from_secs(secs))
+        .expect("lock timeout is only set once");
+}
+
+/// the exit code when `--verify-after-write` finds that a write didn't land. EX_IOERR from
+/// sysexits.h
+pub const VERIFY_FAILED_EXIT_CODE: i32 = 74;
+
+/// whether writes are verified, from `--verify-after-write` or the config
+static VERIFY_AFTER_WRITE: OnceLock<bool> = OnceLock::new();
+
+pub fn set_verify_after_write(verify: bool) {
+    VERIFY_AFTER_WRITE
+        .set(verify)
+        .expect("verify after write is only set once");
+}
+
+/// whether writes to the store are checked by reading them back, from `--verify-after-write` or
+/// `verify_after_write` in the config
+pub fn verify_after_write() -> bool {
+    VERIFY_AFTER_WRITE.get().copied().unwrap_or(false)
+}
+
+/// exit because `--verify-after-write` found the store isn't what was written
+pub fn verify_failed(error: impl std::fmt::Display) -> ! {
+    crate::fail_with_code(
+        VERIFY_FAILED_EXIT_CODE,
+        "verify",
+        error,
+        Some(
+            "the change may not have been saved. check the disk and the store file, then try again",
+        ),
+    )
+}
+
+/// held by a command while it reads, changes and saves the store, so two commands writing at
+/// the same time can't lose each other's changes. unlocked when dropped
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: File,
+}
+
+/// run a (blocking) keyring call on a worker thread so a hung backend can't block us forever.
+/// exits if it doesn't finish within the timeout
+pub fn keyring_call<T: Send + 'static>(
+    entry: Entry,
+    f: impl FnOnce(&Entry) -> T + Send + 'static,
+) -> T {
+    let timeout = *TIMEOUT.get_or_init(|| Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+    let (tx, rx) = mpsc::channel();
+
+    // the thread is detached: if it hangs, it's dropped when we exit
+    thread::spawn(move || {
+        let _ = tx.send(f(&entry));
+    });
+
+    rx.recv_timeout(timeout).unwrap_or_else(|_| {
+        crate::fail(
+            "timeout",
+            format_args!("the keyring didn't respond within {}s", timeout.as_secs()),
+            Some("use --timeout to wait longer"),
+        )
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 /// a store of all of the encrypted variables in cryptenv
 pub struct Store {
+    #[serde(default)]
     vars: HashMap<String, String>,
+    /// other names for variables in `vars`, so one secret can be used under several names without
+    /// storing it twice
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    aliases: HashMap<String, String>,
+    /// when each variable was last set, in seconds since the unix epoch. variables added before
+    /// this was tracked don't have one
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    updated: HashMap<String, u64>,
+}
+
+/// why a variable couldn't be decrypted. none of these contain the value itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    /// the stored value isn't valid base64, so the store file was probably edited by hand
+    InvalidBase64,
+    /// the stored value is too short to have a nonce
+    TooShort,
+    /// the value was encrypted with a different key, or was corrupted
+    WrongKey,
+    /// the decrypted value isn't valid utf8
+    InvalidUtf8,
+    /// the stored value has a format prefix this version of cryptenv doesn't know
+    UnknownFormat,
 }
 
 #[derive(Debug, Clone)]
@@ -20,31 +177,60 @@ pub struct EncryptedVariable<'a> {
     _lifetime: std::marker::PhantomData<&'a ()>,
 }
 
-#[derive(Debug, ZeroizeOnDrop)]
+#[derive(ZeroizeOnDrop)]
 pub struct DecryptedVariable<'a> {
     value: String,
     _lifetime: std::marker::PhantomData<&'a ()>,
 }
 
+/// wraps a secret so that neither `Display` nor `Debug` can print it. anything built from a
+/// decrypted value that could end up in an error message or log should hold it in one of these
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Redacted<T>(pub T);
+
 impl Store {
     /// read the store from disk
-    /// reads from dirs::data_dir()/cryptenv/store.json
+    /// reads from dirs::data_dir()/cryptenv/store.json, or store.<env>.json with `--env`
+    ///
+    /// if there is no data dir or the store doesn't exist yet, the store is empty
     pub fn read() -> Self {
-        let path = Store::get_path();
-
-        if !path.exists() {
-            return Store {
-                vars: HashMap::new(),
-            };
+        if let Some(path) = combined::path() {
+            return Combined::read(path).store;
         }
 
+        let Some(path) = Store::get_path().filter(|p| p.exists()) else {
+            return Store::default();
+        };
+
         let store = std::fs::read_to_string(&path).expect("Could not read store file");
 
         serde_json::from_str(&store).expect("Could not parse store file")
     }
 
+    /// with `--verify-after-write`, the store is read back afterwards and has to match what was
+    /// written
     pub fn save_to_disk(self) {
-        let path = Store::get_path();
+        let expected = verify_after_write().then(|| self.clone());
+        self.write_to_disk();
+
+        if let Some(expected) = expected {
+            if Store::read() != expected {
+                verify_failed("the store read back from disk isn't the one that was written");
+            }
+            verbose!("verified the store after writing it");
+        }
+    }
+
+    fn write_to_disk(self) {
+        if let Some(path) = combined::path() {
+            let mut combined = Combined::read(path);
+            combined.store = self;
+            combined.save_to_disk(path);
+
+            return;
+        }
+
+        let path = Store::get_path().expect("Could not find data directory");
 
         let store = serde_json::to_string(&self).expect("Could not serialize store");
 
@@ -54,107 +240,663 @@ impl Store {
         std::fs::write(&path, store).expect("Could not write store file");
     }
 
+    /// lock the store for writing, waiting up to the lock timeout for another cryptenv to finish.
+    /// take the lock before reading a store that will be saved. exits if it can't be taken
+    pub fn lock() -> StoreLock {
+        let path = match combined::path() {
+            Some(path) => path.to_owned(),
+            None => Store::get_path().expect("Could not find data directory"),
+        };
+        let mut lock_path = path.into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        std::fs::create_dir_all(lock_path.parent().expect("Could not get parent directory"))
+            .expect("Could not create store directory");
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .expect("Could not open store lock");
+
+        let timeout = *LOCK_TIMEOUT.get_or_init(|| Duration::from_secs(DEFAULT_LOCK_TIMEOUT_SECS));
+        let start = Instant::now();
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(TryLockError::WouldBlock) => crate::fail_with_code(
+                    LOCKED_EXIT_CODE,
+                    "locked",
+                    "another cryptenv process is writing the store; try again",
+                    Some("use --store-lock-timeout to wait longer"),
+                ),
+                Err(TryLockError::Error(e)) => panic!("Could not lock the store: {e}"),
+            }
+        }
+        verbose!("locked the store in {:?}", start.elapsed());
+
+        StoreLock { _file: file }
+    }
+
+    /// delete the store file, if there is one
+    pub fn delete_from_disk() {
+        if combined::path().is_some() {
+            Store::default().save_to_disk();
+            return;
+        }
+
+        if let Some(path) = Store::get_path().filter(|p| p.exists()) {
+            std::fs::remove_file(&path).expect("Could not delete store file");
+
+            if verify_after_write() && path.exists() {
+                verify_failed("the store file is still there after deleting it");
+            }
+        }
+    }
+
+    /// the name of the variable `name` refers to, following an alias if it is one
+    pub fn target<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
     pub fn get<'a>(&'a self, name: &'a str) -> Option<EncryptedVariable<'a>> {
-        self.vars.get(name).map(|value| EncryptedVariable {
-            value,
-            _lifetime: std::marker::PhantomData,
-        })
+        self.vars
+            .get(self.target(name))
+            .map(|value| EncryptedVariable {
+                value,
+                _lifetime: std::marker::PhantomData,
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// the size of the store file in bytes, if it exists
+    pub fn size_on_disk() -> Option<u64> {
+        let path = match combined::path() {
+            Some(path) => path.to_owned(),
+            None => Store::get_path()?,
+        };
+
+        std::fs::metadata(path).ok().map(|m| m.len())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.vars.contains_key(self.target(name))
     }
 
+    /// add a variable. adding to an alias changes the variable it points to
     pub fn add(&mut self, key: String, value: &str) {
+        let key = self.aliases.get(&key).cloned().unwrap_or(key);
+        self.updated.insert(key.clone(), now());
         self.vars.insert(key, encrypt(value));
     }
 
-    fn get_path() -> PathBuf {
-        let mut path = dirs::data_dir().expect("Could not find data directory");
+    /// how many whole days ago `name` was last set, if that's known
+    pub fn age_days(&self, name: &str) -> Option<u64> {
+        let updated = self.updated.get(self.target(name))?;
+
+        Some(now().saturating_sub(*updated) / (60 * 60 * 24))
+    }
+
+    /// make `name` another name for `existing`. aliases of aliases point straight at the variable
+    pub fn alias(&mut self, name: String, existing: &str) -> Result<(), String> {
+        if self.vars.contains_key(&name) || self.aliases.contains_key(&name) {
+            return Err(format!("{name} already exists"));
+        }
+        if !self.contains(existing) {
+            return Err(format!("variable {existing} not found"));
+        }
+
+        let target = self.target(existing).to_owned();
+        self.aliases.insert(name, target);
+
+        Ok(())
+    }
+
+    /// every alias, as `(alias, variable)`
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases
+            .iter()
+            .map(|(alias, target)| (alias.as_str(), target.as_str()))
+    }
+
+    /// the aliases whose variable isn't in the store anymore, as `(alias, variable)`
+    pub fn dangling_aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases
+            .iter()
+            .filter(|(_, target)| !self.vars.contains_key(*target))
+            .map(|(alias, target)| (alias.as_str(), target.as_str()))
+    }
+
+    /// like `add`, but encrypted with `key` instead of the cryptenv key
+    pub fn add_with(&mut self, key: &Key<Aes256Gcm>, name: String, value: &str) {
+        self.updated.insert(name.clone(), now());
+        self.vars.insert(name, encrypt_with(key, value));
+    }
+
+    fn get_path() -> Option<PathBuf> {
+        let mut path = dirs::data_dir()?;
         path.push("cryptenv");
-        path.push("store.json");
+        match ENVIRONMENT.get() {
+            Some(env) => path.push(format!("store.{env}.json")),
+            None => path.push("store.json"),
+        }
 
-        path
+        Some(path)
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &str> {
         self.vars.keys().map(|s| s.as_str())
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&str, EncryptedVariable)> {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, EncryptedVariable<'_>)> {
         use itertools::Itertools;
-        self.vars.keys().sorted().map(|k| (k.as_str(), self.get(k).expect("key exists")))
+        self.vars
+            .keys()
+            .sorted()
+            .map(|k| (k.as_str(), self.get(k).expect("key exists")))
+    }
+
+    /// decrypt every variable in `names` that exists in the store
+    ///
+    /// unlike calling `decrypt` on each variable, this only fetches the key from the keyring once,
+    /// which is where most of the time goes for large projects. with `--verbose`, the time spent
+    /// getting the key and decrypting is printed to stderr
+    pub fn decrypt_all<'a>(
+        &'a self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> HashMap<&'a str, Result<DecryptedVariable<'a>, DecryptError>> {
+        let variables: Vec<_> = names
+            .into_iter()
+            .filter_map(|name| self.get(name).map(|v| (name, v)))
+            .collect();
+
+        // don't touch the keyring at all if there's nothing to decrypt
+        if variables.is_empty() {
+            return HashMap::new();
+        }
+
+        let start = Instant::now();
+        let mut key = get_key();
+        verbose!("got key in {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let decrypted: HashMap<_, _> = variables
+            .into_iter()
+            .map(|(name, variable)| {
+                let value = decrypt_with(&key, variable.value).map(|value| DecryptedVariable {
+                    value,
+                    _lifetime: std::marker::PhantomData,
+                });
+
+                (name, value)
+            })
+            .collect();
+        verbose!(
+            "decrypted {} variables in {:?}",
+            decrypted.len(),
+            start.elapsed()
+        );
+
+        key.zeroize();
+
+        decrypted
     }
 }
 
 impl<'a> EncryptedVariable<'a> {
-    pub fn decrypt(self) -> DecryptedVariable<'a> {
-        DecryptedVariable {
-            value: decrypt(self.value),
+    pub fn decrypt(self) -> Result<DecryptedVariable<'a>, DecryptError> {
+        Ok(DecryptedVariable {
+            value: decrypt(self.value)?,
             _lifetime: std::marker::PhantomData,
+        })
+    }
+
+    /// like `decrypt`, but with `key` instead of the cryptenv key
+    pub fn decrypt_with(self, key: &Key<Aes256Gcm>) -> Result<DecryptedVariable<'a>, DecryptError> {
+        Ok(DecryptedVariable {
+            value: decrypt_with(key, self.value)?,
+            _lifetime: std::marker::PhantomData,
+        })
+    }
+}
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            DecryptError::InvalidBase64 => "the stored value is not valid base64",
+            DecryptError::TooShort => "the stored value is too short",
+            DecryptError::WrongKey => {
+                "the value was encrypted with a different key or is corrupted"
+            }
+            DecryptError::InvalidUtf8 => "the decrypted value is not valid utf8",
+            DecryptError::UnknownFormat => "the stored value is in a format cryptenv doesn't know",
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl DecryptError {
+    /// the `kind` of this error in `--error-format json`
+    pub fn kind(&self) -> &'static str {
+        "decrypt"
+    }
+
+    /// what the user can do about this error, if there's anything
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            DecryptError::InvalidBase64 | DecryptError::TooShort => {
+                Some("the store file was probably edited by hand. add the value again")
+            }
+            DecryptError::WrongKey => Some(
+                "check that this is the key (and --env) the value was added with, e.g. with \
+                `cryptenv key fingerprint`",
+            ),
+            DecryptError::InvalidUtf8 => None,
+            DecryptError::UnknownFormat => {
+                Some("it was probably added by a newer version of cryptenv. try upgrading")
+            }
         }
     }
 }
 
-impl<'a> DecryptedVariable<'a> {
+impl std::error::Error for DecryptError {}
+
+impl<T> std::fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T> std::fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl std::fmt::Debug for DecryptedVariable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptedVariable")
+            .field("value", &Redacted(&self.value))
+            .finish()
+    }
+}
+
+impl DecryptedVariable<'_> {
     /// get the decrypted value
-    pub fn value(&'a self) -> &'a str {
+    pub fn value(&self) -> &str {
         &self.value
     }
 }
 
-fn decrypt(value: &str) -> String {
-    let entry = Entry::new("cryptenv", "key").expect("Could not get entry");
+/// the current time in seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// gets the key from the `CRYPTENV_KEY` env var if it's set, then the key command if there is one,
+/// otherwise from the keyring, creating it if it doesn't exist yet
+fn get_key() -> Key<Aes256Gcm> {
+    if let Some(key) = find_key() {
+        return key;
+    }
+
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    let secret = Zeroizing::new(key.to_vec());
+
+    keyring_call(key_entry(), move |entry| entry.set_secret(&secret))
+        .expect("Could not set secret");
+
+    key
+}
+
+/// gets the key from the `CRYPTENV_KEY` env var, the key command, or the keyring, without creating
+/// it
+fn find_key() -> Option<Key<Aes256Gcm>> {
+    if let Ok(mut encoded) = std::env::var("CRYPTENV_KEY") {
+        let key = parse_key(&encoded).unwrap_or_else(|e| fail!("key", "invalid CRYPTENV_KEY: {e}"));
+        encoded.zeroize();
 
-    let mut key = match entry.get_secret() {
-        // TODO: un needed clone
-        Ok(key) => Key::<Aes256Gcm>::clone_from_slice(&key),
-        Err(_) => {
-            let key = Aes256Gcm::generate_key(&mut OsRng);
+        return Some(key);
+    }
+
+    // the key could be meant to come from a key command in the broken config. using (or making)
+    // a keyring key instead would encrypt with the wrong key
+    if let Some(Err(e)) = KEY_COMMAND.get() {
+        crate::fail(
+            "config",
+            e,
+            Some("fix the config, or set CRYPTENV_KEY or CRYPTENV_KEY_COMMAND"),
+        );
+    }
+
+    if let Some(command) = key_command() {
+        // the command may ask for a password, so it only runs once per invocation
+        let cached = COMMAND_KEY.get_or_init(|| {
+            let mut key = run_key_command(command);
+            let mut bytes = Zeroizing::new([0; 32]);
+            bytes.copy_from_slice(&key);
+            key.zeroize();
+
+            bytes
+        });
+
+        return Some(Key::<Aes256Gcm>::clone_from_slice(cached.as_slice()));
+    }
 
-            entry
-                .set_secret(key.as_ref())
-                .expect("Could not set secret");
+    // TODO: un needed clone
+    let mut secret = keyring_call(key_entry(), |entry| entry.get_secret()).ok()?;
+    let key = Key::<Aes256Gcm>::clone_from_slice(&secret);
+    secret.zeroize();
 
-            key
+    Some(key)
+}
+
+/// the command that prints the key, from `CRYPTENV_KEY_COMMAND` or `key_command` in the config
+pub fn key_command() -> Option<&'static str> {
+    KEY_COMMAND.get()?.as_ref().ok()?.as_deref()
+}
+
+/// use `command` to get the key, or the error from reading the config it would come from. with
+/// an error, anything that needs a key other than `CRYPTENV_KEY` exits with it. must be called
+/// before anything touches the key
+pub fn set_key_command(command: Result<Option<String>, String>) {
+    let command = command.map(|command| command.filter(|command| !command.trim().is_empty()));
+
+    KEY_COMMAND
+        .set(command)
+        .expect("key command is only set once");
+}
+
+/// run the key command with `sh -c` and parse its output like `CRYPTENV_KEY`. exits if it fails
+fn run_key_command(command: &str) -> Key<Aes256Gcm> {
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c").arg(command).stderr(process::Stdio::inherit());
+    // so one command can hand out a different key for each environment
+    if let Some(env) = ENVIRONMENT.get() {
+        cmd.env("CRYPTENV_ENV", env);
+    }
+
+    let output = cmd.output().unwrap_or_else(|e| {
+        crate::fail(
+            "key",
+            format_args!("could not run the key command `{command}`: {e}"),
+            Some("check key_command in the config, or CRYPTENV_KEY_COMMAND"),
+        )
+    });
+
+    if !output.status.success() {
+        crate::fail(
+            "key",
+            format_args!("the key command `{command}` failed with {}", output.status),
+            Some("run it yourself to see what went wrong"),
+        );
+    }
+
+    let mut stdout = output.stdout;
+    let key = std::str::from_utf8(&stdout)
+        .map_err(|_| "its output isn't valid utf8")
+        .and_then(parse_key);
+    stdout.zeroize();
+
+    key.unwrap_or_else(|e| {
+        crate::fail(
+            "key",
+            format_args!("the key command `{command}` didn't print a valid key: {e}"),
+            Some("it should print only the key, as 32 bytes in base64 or hex"),
+        )
+    })
+}
+
+/// the keyring entry the key lives in. each environment gets its own
+fn key_entry() -> Entry {
+    let account = match ENVIRONMENT.get() {
+        Some(env) => format!("key.{env}"),
+        None => "key".to_owned(),
+    };
+
+    Entry::new("cryptenv", &account).expect("Could not get entry")
+}
+
+/// delete the key from the keyring. a new one is made the next time something is encrypted
+pub fn delete_key() -> Result<(), keyring::Error> {
+    match keyring_call(key_entry(), |entry| entry.delete_credential()) {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// a short, non-secret identifier for the key: the first 8 bytes of its SHA-256 hash, in hex.
+/// enough to tell whether two machines have the same key, but not to recover it
+///
+/// returns None if there's no key yet
+pub fn fingerprint() -> Option<String> {
+    let mut key = find_key()?;
+    let hash = Sha256::digest(key.as_slice());
+    key.zeroize();
+
+    Some(hex::encode(&hash[..8]))
+}
+
+/// makes sure there is a key to encrypt with, creating one in the keyring if there isn't
+pub fn init_key() {
+    get_key().zeroize();
+}
+
+/// parses a 32 byte key encoded as either base64 or hex. the error never contains the key itself
+fn parse_key(encoded: &str) -> Result<Key<Aes256Gcm>, &'static str> {
+    use base64::prelude::*;
+    let encoded = encoded.trim();
+
+    let mut base64 = BASE64_STANDARD
+        .decode(encoded)
+        .ok()
+        .filter(|k| k.len() == 32);
+    let mut hex = hex::decode(encoded).ok().filter(|k| k.len() == 32);
+
+    let key = match (&base64, &hex) {
+        (Some(key), None) | (None, Some(key)) => Ok(Key::<Aes256Gcm>::clone_from_slice(key)),
+        (Some(_), Some(_)) => Err("the key is ambiguous, it is valid as both base64 and hex"),
+        (None, None) => {
+            Err("expected 32 bytes encoded as base64 (44 characters) or hex (64 characters)")
         }
     };
 
-    let cipher = Aes256Gcm::new(&key);
+    base64.zeroize();
+    hex.zeroize();
+
+    key
+}
+
+fn decrypt(value: &str) -> Result<String, DecryptError> {
+    let mut key = get_key();
+    let decrypted = decrypt_with(&key, value);
+
+    key.zeroize();
+
+    decrypted
+}
+
+pub fn decrypt_with(key: &Key<Aes256Gcm>, value: &str) -> Result<String, DecryptError> {
+    let decrypted = match value.strip_prefix(ENVELOPE_PREFIX) {
+        Some(envelope) => open_envelope(key, envelope)?,
+        // base64 has no `:`, so a value with one is in some other format
+        None if value.contains(':') => return Err(DecryptError::UnknownFormat),
+        None => open(key, value)?,
+    };
+
+    String::from_utf8(decrypted).map_err(|_| DecryptError::InvalidUtf8)
+}
+
+/// decrypt an envelope with whichever copy of its data key `key` can decrypt
+fn open_envelope(key: &Key<Aes256Gcm>, envelope: &str) -> Result<Vec<u8>, DecryptError> {
+    let (wrapped, data) = envelope.rsplit_once('.').ok_or(DecryptError::TooShort)?;
+
+    let mut data_key = wrapped
+        .split('.')
+        .find_map(|wrapped| open(key, wrapped).ok())
+        .filter(|data_key| data_key.len() == 32)
+        .ok_or(DecryptError::WrongKey)?;
+    let decrypted = open(Key::<Aes256Gcm>::from_slice(&data_key), data);
+    data_key.zeroize();
+
+    decrypted
+}
+
+/// decrypt base64 encoded `nonce || ciphertext`
+fn open(key: &Key<Aes256Gcm>, value: &str) -> Result<Vec<u8>, DecryptError> {
+    let cipher = Aes256Gcm::new(key);
     use base64::prelude::*;
     let data = BASE64_STANDARD
         .decode(value)
-        .expect("value is valid base64");
+        .map_err(|_| DecryptError::InvalidBase64)?;
+
+    if data.len() < 12 {
+        return Err(DecryptError::TooShort);
+    }
     let nonce = data[0..12].into();
 
-    key.zeroize();
+    cipher
+        .decrypt(nonce, &data[12..])
+        .map_err(|_| DecryptError::WrongKey)
+}
+
+/// encrypt with the cryptenv key, and also with every key in `CRYPTENV_ENVELOPE_KEYS` if it's set
+fn encrypt(value: &str) -> String {
+    let mut keys = envelope_keys();
+    let mut key = get_key();
+
+    let encrypted = if keys.is_empty() {
+        encrypt_with(&key, value)
+    } else {
+        verbose!("encrypting with {} envelope keys", keys.len());
+        keys.insert(0, key);
+        encrypt_envelope(&keys, value)
+    };
 
-    let decrypted = cipher.decrypt(nonce, &data[12..]).unwrap();
+    key.zeroize();
+    keys.iter_mut().for_each(|key| key.zeroize());
 
-    String::from_utf8(decrypted).expect("decrypted text is valid utf8")
+    encrypted
 }
 
-fn encrypt(value: &str) -> String {
-    let entry = Entry::new("cryptenv", "key").expect("Could not get entry");
+pub fn encrypt_with(key: &Key<Aes256Gcm>, value: &str) -> String {
+    seal(key, value.as_bytes())
+}
 
-    let mut key = match entry.get_secret() {
-        // TODO: un needed clone
-        Ok(key) => Key::<Aes256Gcm>::clone_from_slice(&key),
-        Err(_) => {
-            let key = Aes256Gcm::generate_key(&mut OsRng);
+/// encrypt `value` so that any one of `keys` can decrypt it
+pub fn encrypt_envelope(keys: &[Key<Aes256Gcm>], value: &str) -> String {
+    let mut data_key = Aes256Gcm::generate_key(&mut OsRng);
 
-            entry
-                .set_secret(key.as_ref())
-                .expect("Could not set secret");
+    let mut fields = keys
+        .iter()
+        .map(|key| seal(key, data_key.as_slice()))
+        .collect::<Vec<_>>();
+    fields.push(encrypt_with(&data_key, value));
+    data_key.zeroize();
 
-            key
-        }
-    };
+    format!("{ENVELOPE_PREFIX}{}", fields.join("."))
+}
 
-    let cipher = Aes256Gcm::new(&key);
+/// encrypt `data` with a random nonce, as base64 encoded `nonce || ciphertext`
+fn seal(key: &Key<Aes256Gcm>, data: &[u8]) -> String {
+    let cipher = Aes256Gcm::new(key);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let encrypted = cipher.encrypt(&nonce, value.as_bytes()).unwrap();
-
-    key.zeroize();
+    let encrypted = cipher.encrypt(&nonce, data).unwrap();
 
     // TODO: lots of copying here
     let data: Vec<u8> = [nonce.as_slice(), &encrypted].concat();
     use base64::prelude::*;
     BASE64_STANDARD.encode(data)
 }
+
+/// the extra keys in `CRYPTENV_ENVELOPE_KEYS` (comma separated, like `CRYPTENV_KEY`) that new
+/// values are also encrypted with, e.g. the next key during a rotation. exits if one is invalid
+fn envelope_keys() -> Vec<Key<Aes256Gcm>> {
+    let Ok(mut encoded) = std::env::var("CRYPTENV_ENVELOPE_KEYS") else {
+        return Vec::new();
+    };
+
+    let keys = encoded
+        .split(',')
+        .filter(|key| !key.trim().is_empty())
+        .map(|key| {
+            parse_key(key)
+                .unwrap_or_else(|e| fail!("key", "invalid key in CRYPTENV_ENVELOPE_KEYS: {e}"))
+        })
+        .collect();
+    encoded.zeroize();
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{builder::TypedValueParser, CommandFactory};
+
+    use super::*;
+
+    const SECRET: &str = "hunter2-very-secret";
+
+    #[test]
+    fn redacted_hides_the_value() {
+        assert_eq!(Redacted(SECRET).to_string(), "<redacted>");
+        assert_eq!(format!("{:?}", Redacted(SECRET)), "<redacted>");
+    }
+
+    #[test]
+    fn decrypted_variable_debug_hides_the_value() {
+        let variable = DecryptedVariable {
+            value: SECRET.to_owned(),
+            _lifetime: std::marker::PhantomData,
+        };
+
+        assert!(!format!("{variable:?}").contains(SECRET));
+    }
+
+    #[test]
+    fn decrypt_errors_dont_contain_the_value() {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        let other = Aes256Gcm::generate_key(&mut OsRng);
+        let encrypted = encrypt_with(&key, SECRET);
+
+        let errors = [
+            decrypt_with(&other, &encrypted).unwrap_err(),
+            decrypt_with(&key, SECRET).unwrap_err(),
+            decrypt_with(&key, &format!("v9:{SECRET}")).unwrap_err(),
+            DecryptError::TooShort,
+            DecryptError::InvalidUtf8,
+        ];
+
+        for error in errors {
+            assert!(!error.to_string().contains(SECRET), "{error}");
+            assert!(!error.hint().unwrap_or_default().contains(SECRET));
+        }
+    }
+
+    #[test]
+    fn bad_set_values_are_redacted() {
+        let cmd = crate::Args::command();
+        let value = std::ffi::OsString::from(SECRET);
+
+        let error = crate::SecretOverrideParser
+            .parse_ref(&cmd, None, &value)
+            .unwrap_err();
+
+        assert!(!error.to_string().contains(SECRET), "{error}");
+    }
+}